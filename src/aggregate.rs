@@ -0,0 +1,170 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use data::Value;
+use table::Cell;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl Aggregate {
+    /// Folds every value of a group into a single cell. Nulls are skipped, `sum` and `avg` also
+    /// skip values that aren't numbers, `min` and `max` only compare values of one kind: the
+    /// numbers of the group, or the values like its first one if it has none.
+    pub fn apply(&self, values: &[Value]) -> Cell {
+        match *self {
+            Aggregate::Count => {
+                vec![Value::Int(values.iter().filter(|v| **v != Value::Null).count() as i64)]
+            }
+            Aggregate::Sum => {
+                let numbers = numbers(values);
+                // Ints sum to an int unless the sum overflows
+                let ints = if numbers.iter().all(|v| is_int(v)) {
+                    numbers.iter().fold(Some(0i64), |sum, v| {
+                        sum.and_then(|sum| sum.checked_add(as_int(v)))
+                    })
+                } else {
+                    None
+                };
+                match ints {
+                    Some(sum) => vec![Value::Int(sum)],
+                    None => {
+                        vec![Value::Float(numbers.iter().fold(0.0, |sum, v| sum + as_float(v)))]
+                    }
+                }
+            }
+            Aggregate::Avg => {
+                let numbers = numbers(values);
+                if numbers.is_empty() {
+                    return vec![];
+                }
+                let total = numbers.iter().fold(0.0, |sum, v| sum + as_float(v));
+                vec![Value::Float(total / numbers.len() as f64)]
+            }
+            Aggregate::Min => extreme(values, Ordering::Less),
+            Aggregate::Max => extreme(values, Ordering::Greater),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::Sum => write!(f, "sum"),
+            Aggregate::Min => write!(f, "min"),
+            Aggregate::Max => write!(f, "max"),
+            Aggregate::Avg => write!(f, "avg"),
+        }
+    }
+}
+
+fn extreme(values: &[Value], wanted: Ordering) -> Cell {
+    let mut candidates = numbers(values);
+    if candidates.is_empty() {
+        if let Some(first) = values.iter().find(|v| **v != Value::Null) {
+            candidates = values.iter().filter(|v| first.compare(v).is_some()).collect();
+        }
+    }
+
+    let mut best: Option<&Value> = None;
+    // NaN doesn't compare with itself and is skipped like the values of other kinds
+    for value in candidates.into_iter().filter(|v| v.compare(v).is_some()) {
+        best = match best {
+            Some(b) if value.compare(b) != Some(wanted) => Some(b),
+            _ => Some(value),
+        };
+    }
+    best.cloned().into_iter().collect()
+}
+
+fn numbers(values: &[Value]) -> Vec<&Value> {
+    values.iter()
+          .filter(|v| {
+              match **v {
+                  Value::Int(_) | Value::Float(_) => true,
+                  _ => false,
+              }
+          })
+          .collect()
+}
+
+fn is_int(value: &Value) -> bool {
+    match *value {
+        Value::Int(_) => true,
+        _ => false,
+    }
+}
+
+fn as_int(value: &Value) -> i64 {
+    match *value {
+        Value::Int(i) => i,
+        _ => 0,
+    }
+}
+
+fn as_float(value: &Value) -> f64 {
+    match *value {
+        Value::Int(i) => i as f64,
+        Value::Float(f) => f,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f64, i64};
+
+    use super::Aggregate;
+    use data::Value;
+
+    #[test]
+    fn aggregates() {
+        let values = vec![Value::Int(3),
+                          Value::from("n/a"),
+                          Value::Null,
+                          Value::Int(5),
+                          Value::Int(1)];
+
+        assert_eq!(vec![Value::Int(4)], Aggregate::Count.apply(&values));
+        assert_eq!(vec![Value::Int(9)], Aggregate::Sum.apply(&values));
+        assert_eq!(vec![Value::Float(3.0)], Aggregate::Avg.apply(&values));
+        assert_eq!(vec![Value::Int(1)], Aggregate::Min.apply(&values));
+        assert_eq!(vec![Value::Int(5)], Aggregate::Max.apply(&values));
+    }
+
+    #[test]
+    fn extremes_of_one_kind() {
+        let values = vec![Value::Null, Value::from("b"), Value::Bool(true), Value::from("a")];
+        assert_eq!(vec![Value::from("a")], Aggregate::Min.apply(&values));
+        assert_eq!(vec![Value::from("b")], Aggregate::Max.apply(&values));
+
+        let values = vec![Value::Float(f64::NAN), Value::Float(0.5), Value::Int(2)];
+        assert_eq!(vec![Value::Float(0.5)], Aggregate::Min.apply(&values));
+        assert_eq!(vec![Value::Int(2)], Aggregate::Max.apply(&values));
+        assert_eq!(Vec::<Value>::new(), Aggregate::Max.apply(&[Value::Null]));
+    }
+
+    #[test]
+    fn mixed_numbers() {
+        let values = vec![Value::Int(1), Value::Float(0.5)];
+
+        assert_eq!(vec![Value::Float(1.5)], Aggregate::Sum.apply(&values));
+        assert_eq!(vec![Value::Int(0)], Aggregate::Sum.apply(&[]));
+        assert_eq!(Vec::<Value>::new(), Aggregate::Avg.apply(&[]));
+    }
+
+    #[test]
+    fn sum_overflow() {
+        let values = vec![Value::Int(i64::MAX), Value::Int(1)];
+
+        assert_eq!(vec![Value::Float(i64::MAX as f64 + 1.0)], Aggregate::Sum.apply(&values));
+        assert_eq!(vec![Value::Int(i64::MAX)], Aggregate::Sum.apply(&values[..1]));
+    }
+}
@@ -1,4 +1,10 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use aggregate::Aggregate;
+use data::{parse_time, Basis, Span, Value};
 use grammar;
+use storage::Row;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Comparator {
@@ -17,11 +23,23 @@ impl Comparator {
             Comparator::Greater => left > right,
             Comparator::GreaterOrEqual => left >= right,
             Comparator::Less => left < right,
-            Comparator::LessOrEqual => left >= right,
+            Comparator::LessOrEqual => left <= right,
             _ => false,
         }
     }
 
+    /// Tests a time against a span as a whole, `t>1970` holds from 1971 and `t<=1970` until
+    /// the end of 1970. Contains is the same as equality.
+    pub fn test_time(&self, time: i64, span: &Span) -> bool {
+        match *self {
+            Comparator::Contains | Comparator::Equal => span.start <= time && time < span.end,
+            Comparator::Greater => time >= span.end,
+            Comparator::GreaterOrEqual => time >= span.start,
+            Comparator::Less => time < span.start,
+            Comparator::LessOrEqual => time < span.end,
+        }
+    }
+
     pub fn test_str(&self, left: &str, right: &str) -> bool {
         match *self {
             Comparator::Contains => left.contains(right),
@@ -29,29 +47,356 @@ impl Comparator {
             Comparator::Greater => left > right,
             Comparator::GreaterOrEqual => left >= right,
             Comparator::Less => left < right,
-            Comparator::LessOrEqual => left >= right,
+            Comparator::LessOrEqual => left <= right,
         }
     }
+
+    pub fn test_value(&self, left: &Value, right: &Value) -> bool {
+        // Nulls have no text to contain anything
+        if *self == Comparator::Contains {
+            return *left != Value::Null && left.as_text().contains(&*right.as_text());
+        }
+
+        match (self, left.compare(right)) {
+            (&Comparator::Equal, Some(Ordering::Equal)) => true,
+            (&Comparator::Greater, Some(Ordering::Greater)) => true,
+            (&Comparator::GreaterOrEqual, Some(Ordering::Greater)) => true,
+            (&Comparator::GreaterOrEqual, Some(Ordering::Equal)) => true,
+            (&Comparator::Less, Some(Ordering::Less)) => true,
+            (&Comparator::LessOrEqual, Some(Ordering::Less)) => true,
+            (&Comparator::LessOrEqual, Some(Ordering::Equal)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            Comparator::Contains => ":",
+            Comparator::Equal => "=",
+            Comparator::Greater => ">",
+            Comparator::GreaterOrEqual => ">=",
+            Comparator::Less => "<",
+            Comparator::LessOrEqual => "<=",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
+/// Constraints on each field of a datum, all of them must hold for a datum to match.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Predicates {
-    pub e: Option<(usize, Comparator)>,
-    pub a: Option<(String, Comparator)>,
-    pub v: Option<(String, Comparator)>,
-    pub t: Option<(usize, Comparator)>,
+    pub e: Vec<(usize, Comparator)>,
+    pub a: Vec<(String, Comparator)>,
+    pub v: Vec<(Value, Comparator)>,
+    pub t: Vec<(Span, Comparator)>,
+    /// Attributes the datum's entity has a non-null value for
+    pub has: Vec<String>,
 }
 
 impl Predicates {
-    pub fn new(e: Option<(usize, Comparator)>, a: Option<(String, Comparator)>,
-               v: Option<(String, Comparator)>, t: Option<(usize, Comparator)>)
+    pub fn new(e: Vec<(usize, Comparator)>, a: Vec<(String, Comparator)>,
+               v: Vec<(Value, Comparator)>, t: Vec<(Span, Comparator)>)
                -> Predicates {
         Predicates {
             e: e,
             a: a,
             v: v,
             t: t,
+            has: vec![],
+        }
+    }
+
+    pub fn empty() -> Predicates {
+        Predicates::new(vec![], vec![], vec![], vec![])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.e.is_empty() && self.a.is_empty() && self.v.is_empty() && self.t.is_empty() &&
+        self.has.is_empty()
+    }
+}
+
+impl fmt::Display for Predicates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        for &(e, ref comp) in &self.e {
+            parts.push(format!("e{}{}", comp, e));
+        }
+        for &(ref a, ref comp) in &self.a {
+            parts.push(format!("a{}{}", comp, quote(a)));
+        }
+        for &(ref v, ref comp) in &self.v {
+            let text = v.as_text();
+            // Strings that would read back as another type keep their quotes
+            match *v {
+                Value::Str(_) if literal(&text) != *v => parts.push(format!("v{}'{}'", comp, text)),
+                _ => parts.push(format!("v{}{}", comp, quote(&text))),
+            }
+        }
+        for &(ref t, ref comp) in &self.t {
+            parts.push(format!("t{}{}", comp, t));
         }
+        for a in &self.has {
+            parts.push(format!("has({})", a));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+fn quote(val: &str) -> String {
+    if val.contains(' ') {
+        format!("'{}'", val)
+    } else {
+        val.to_owned()
+    }
+}
+
+/// The reference attribute a join walks through. A forward path matches the entities holding
+/// the reference, `reverse` (written `entity/_attribute`) matches the referenced entities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefPath {
+    pub attribute: String,
+    pub reverse: bool,
+}
+
+impl RefPath {
+    pub fn new<S>(attribute: S, reverse: bool) -> RefPath
+        where S: Into<String>
+    {
+        RefPath {
+            attribute: attribute.into(),
+            reverse: reverse,
+        }
+    }
+
+    pub fn parse(raw: &str) -> RefPath {
+        let mut parts = raw.splitn(2, '/');
+        let entity = parts.next().unwrap_or("");
+        let name = parts.next().unwrap_or("");
+
+        if name.starts_with('_') {
+            RefPath::new(format!("{}/{}", entity, &name[1..]), true)
+        } else {
+            RefPath::new(raw, false)
+        }
+    }
+}
+
+impl fmt::Display for RefPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = self.attribute.splitn(2, '/');
+        match (self.reverse, parts.next(), parts.next()) {
+            (true, Some(entity), Some(name)) => write!(f, "{}/_{}", entity, name),
+            _ => write!(f, "{}", self.attribute),
+        }
+    }
+}
+
+/// An attribute of the projected entity, or of an entity reached by following `refs` from it.
+/// Written as dot separated attributes, e.g. `track/album.album/name`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub refs: Vec<RefPath>,
+    pub attribute: String,
+}
+
+impl Column {
+    pub fn from_path(mut path: Vec<String>) -> Column {
+        let attribute = path.pop().unwrap();
+        Column {
+            refs: path.iter().map(|r| RefPath::parse(r)).collect(),
+            attribute: attribute,
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for path in &self.refs {
+            try!(write!(f, "{}.", path));
+        }
+        write!(f, "{}", self.attribute)
+    }
+}
+
+/// A part of a result row: one of a datum's fields, the transaction that added it or an
+/// attribute of its entity.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    E,
+    A,
+    V,
+    T,
+    Tx,
+    Attribute(Column),
+}
+
+impl Field {
+    /// `a`, `v`, `t` and `tx` only exist on individual datums, entity ids and attributes don't.
+    pub fn is_datum_field(&self) -> bool {
+        match *self {
+            Field::A | Field::V | Field::T | Field::Tx => true,
+            Field::E | Field::Attribute(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Field::E => write!(f, "e"),
+            Field::A => write!(f, "a"),
+            Field::V => write!(f, "v"),
+            Field::T => write!(f, "t"),
+            Field::Tx => write!(f, "tx"),
+            Field::Attribute(ref c) => write!(f, "{}", c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    Field(Field),
+    Aggregate(Aggregate, Field),
+}
+
+impl Selection {
+    pub fn field(&self) -> &Field {
+        match *self {
+            Selection::Field(ref f) | Selection::Aggregate(_, ref f) => f,
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Selection::Field(ref field) => write!(f, "{}", field),
+            Selection::Aggregate(ref agg, ref field) => write!(f, "{}({})", agg, field),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub field: Field,
+    pub direction: Direction,
+}
+
+impl Order {
+    pub fn new(field: Field, direction: Direction) -> Order {
+        Order {
+            field: field,
+            direction: direction,
+        }
+    }
+
+    /// Values of different types are ordered with `Value::total_cmp`. Only datum fields can be
+    /// ordered on, columns are resolved after ordering.
+    pub fn compare(&self, left: &Row, right: &Row) -> Ordering {
+        let ordering = match self.field {
+            Field::E => left.e.cmp(&right.e),
+            Field::A => left.a.cmp(&right.a),
+            Field::V => left.v.total_cmp(&right.v),
+            Field::T => left.t.cmp(&right.t),
+            Field::Tx => left.tx.cmp(&right.tx),
+            Field::Attribute(_) => Ordering::Equal,
+        };
+
+        match self.direction {
+            Direction::Ascending => ordering,
+            Direction::Descending => ordering.reverse(),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.direction {
+            Direction::Ascending => write!(f, "{}", self.field),
+            Direction::Descending => write!(f, "{} desc", self.field),
+        }
+    }
+}
+
+/// A full query: the filter to run and what to show of its results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub ast: AstNode,
+    pub projection: Vec<Selection>,
+    pub group_by: Vec<Field>,
+    pub basis: Basis,
+    pub order: Vec<Order>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Query {
+    pub fn new(ast: AstNode, projection: Vec<Selection>, group_by: Vec<Field>) -> Query {
+        Query {
+            ast: ast,
+            projection: projection,
+            group_by: group_by,
+            basis: Basis::Current,
+            order: vec![],
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    pub fn with_modifiers(self,
+                          basis: Basis,
+                          order: Vec<Order>,
+                          limit: Option<usize>,
+                          offset: usize)
+                          -> Query {
+        Query {
+            basis: basis,
+            order: order,
+            limit: limit,
+            offset: offset,
+            ..self
+        }
+    }
+
+    /// Sorts the filtered datums on each `order by` field in turn. The sort is stable, so ties
+    /// keep their insertion order.
+    pub fn sort(&self, datums: &mut Vec<Row>) {
+        datums.sort_by(|l, r| {
+            self.order
+                .iter()
+                .map(|o| o.compare(l, r))
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        })
+    }
+
+    /// Skips `offset` rows and keeps at most `limit` of the rest.
+    pub fn page<T>(&self, rows: Vec<T>) -> Vec<T> {
+        let limit = self.limit.unwrap_or(rows.len());
+        rows.into_iter().skip(self.offset).take(limit).collect()
+    }
+
+    pub fn parse(query: &str) -> Result<Query, grammar::ParseError> {
+        grammar::query(query)
+    }
+
+    pub fn is_aggregate(&self) -> bool {
+        !self.group_by.is_empty() ||
+        self.projection.iter().any(|s| {
+            match *s {
+                Selection::Aggregate(_, _) => true,
+                Selection::Field(_) => false,
+            }
+        })
     }
 }
 
@@ -59,9 +404,57 @@ impl Predicates {
 pub enum AstNode {
     True,
     Or(Box<AstNode>, Box<AstNode>),
+    And(Box<AstNode>, Box<AstNode>),
+    Not(Box<AstNode>),
     Expression(Predicates),
     Join(Predicates, Box<AstNode>),
-    CachedJoin(Predicates, usize),
+    RefJoin(Predicates, RefPath, Box<AstNode>),
+}
+
+/// A single conjunct produced by the parser, bare predicates are merged into one expression.
+#[derive(Debug)]
+pub enum Term {
+    Pred((String, String, Option<AstNode>, Comparator)),
+    Node(AstNode),
+}
+
+impl Term {
+    pub fn into_node(self) -> AstNode {
+        match self {
+            Term::Pred(p) => AstNode::from_parser(vec![p]),
+            Term::Node(n) => n,
+        }
+    }
+}
+
+/// Fails predicates whose value doesn't read as an entity id or time, the parser reports what
+/// it expected instead.
+pub fn check_pred(pred: (String, String, Option<AstNode>, Comparator))
+                  -> Result<(String, String, Option<AstNode>, Comparator), &'static str> {
+    let expected = {
+        let val = unquote(&pred.1).0;
+        match &pred.0[..] {
+            "e" if pred.2.is_none() && val.parse::<usize>().is_err() => Some("an entity id"),
+            "t" if Span::parse(val).is_none() => Some("a year, date or time"),
+            _ => None,
+        }
+    };
+    match expected {
+        Some(expected) => Err(expected),
+        None => Ok(pred),
+    }
+}
+
+/// Strips the quotes off a predicate's value, telling whether it was quoted.
+fn unquote(raw: &str) -> (&str, bool) {
+    let val = raw.trim_matches(|c| c == '\'' || c == '"');
+    (val, val.len() != raw.len())
+}
+
+/// Reads the unquoted value of a `v` predicate, dates and times are compared as timestamps the
+/// way imported date columns are stored.
+fn literal(raw: &str) -> Value {
+    parse_time(raw).map(Value::Timestamp).unwrap_or_else(|| Value::parse(raw))
 }
 
 impl AstNode {
@@ -69,52 +462,84 @@ impl AstNode {
         grammar::ast(query)
     }
 
+    pub fn from_terms(terms: Vec<Term>) -> AstNode {
+        let mut preds = vec![];
+        let mut nodes = vec![];
+
+        for term in terms {
+            match term {
+                Term::Pred(p) => preds.push(p),
+                Term::Node(n) => nodes.push(n),
+            }
+        }
+
+        if !preds.is_empty() {
+            nodes.insert(0, AstNode::from_parser(preds));
+        }
+
+        Self::conjunction(nodes)
+    }
+
     pub fn from_parser(preds: Vec<(String, String, Option<AstNode>, Comparator)>) -> AstNode {
-        let mut e = None;
-        let mut a = None;
-        let mut v = None;
-        let mut t = None;
-        let mut child = None;
+        let mut predicates = Predicates::empty();
+        let mut children = vec![];
 
-        for (name, val, ast, comp) in preds {
+        for (name, raw, ast, comp) in preds {
+            let (val, quoted) = unquote(&raw);
+            // Entity ids and times were checked by the parser
             match name.as_ref() {
                 "e" => {
-                    if comp == Comparator::Contains {
-                        e = Some((0, comp));
-                        child = Some(Box::new(ast.unwrap()));
-                    } else {
-                        e = Some((val.parse::<usize>().unwrap(), comp))
+                    match ast {
+                        Some(child) => children.push((None, child)),
+                        None => predicates.e.push((val.parse::<usize>().unwrap(), comp)),
                     }
                 }
-                "a" => a = Some((val, comp)),
-                "v" => v = Some((val, comp)),
-                "t" => t = Some((val.parse::<usize>().unwrap(), comp)),
+                "ref" => children.push((Some(RefPath::parse(val)), ast.unwrap())),
+                "a" => predicates.a.push((val.to_owned(), comp)),
+                "v" if quoted => predicates.v.push((Value::Str(val.to_owned()), comp)),
+                "v" => predicates.v.push((literal(val), comp)),
+                "t" => predicates.t.push((Span::parse(val).unwrap(), comp)),
+                "has" => predicates.has.push(val.to_owned()),
                 _ => continue,
             }
         }
 
-        if child.is_some() {
-            AstNode::Join(Predicates {
-                              e: e,
-                              a: a,
-                              v: v,
-                              t: t,
-                          },
-                          child.unwrap())
-        } else {
-            AstNode::Expression(Predicates {
-                e: e,
-                a: a,
-                v: v,
-                t: t,
-            })
+        if children.is_empty() {
+            return AstNode::Expression(predicates);
         }
+
+        // The first join carries the expression's predicates, the others only constrain the entity
+        let mut predicates = Some(predicates);
+        let joins = children.into_iter()
+                            .map(|(path, child)| {
+                                let preds = predicates.take().unwrap_or_else(Predicates::empty);
+                                match path {
+                                    Some(path) => AstNode::RefJoin(preds, path, Box::new(child)),
+                                    None => AstNode::Join(preds, Box::new(child)),
+                                }
+                            })
+                            .collect();
+        Self::conjunction(joins)
+    }
+
+    fn conjunction(nodes: Vec<AstNode>) -> AstNode {
+        let mut rev = nodes.into_iter().rev();
+        let last = rev.next().unwrap();
+        rev.fold(last, |acc, node| AstNode::And(Box::new(node), Box::new(acc)))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{AstNode, Predicates, Comparator};
+    use super::{AstNode, Column, Direction, Field, Order, Predicates, Comparator, Query, RefPath,
+                Selection};
+    use aggregate::Aggregate;
+    use data::{parse_time, year, Basis, Datum, Moment, Span, Value};
+    use storage::Row;
+
+    fn span(raw: &str) -> Span {
+        Span::parse(raw).unwrap()
+    }
 
     #[test]
     fn parse_truthy() {
@@ -130,10 +555,11 @@ mod tests {
     fn parse_id_equality() {
         let qs = ["e=1", "   e=1 ", "e=1  "];
         let ast = AstNode::Expression(Predicates {
-            e: Some((1, Comparator::Equal)),
-            a: None,
-            v: None,
-            t: None,
+            e: vec![(1, Comparator::Equal)],
+            a: vec![],
+            v: vec![],
+            t: vec![],
+            has: vec![],
         });
 
         for q in &qs {
@@ -145,22 +571,25 @@ mod tests {
     fn parse_multiple_predicates() {
         let qs = ["a=foo e=3 t=1  v=bar", " v=bar  t=1 ", "e=1 a=foo"];
         let asts = [AstNode::Expression(Predicates {
-                        e: Some((3, Comparator::Equal)),
-                        a: Some(("foo".to_owned(), Comparator::Equal)),
-                        v: Some(("bar".to_owned(), Comparator::Equal)),
-                        t: Some((1, Comparator::Equal)),
+                        e: vec![(3, Comparator::Equal)],
+                        a: vec![("foo".to_owned(), Comparator::Equal)],
+                        v: vec![(Value::from("bar"), Comparator::Equal)],
+                        t: vec![(span("1"), Comparator::Equal)],
+                        has: vec![],
                     }),
                     AstNode::Expression(Predicates {
-                        e: None,
-                        a: None,
-                        v: Some(("bar".to_owned(), Comparator::Equal)),
-                        t: Some((1, Comparator::Equal)),
+                        e: vec![],
+                        a: vec![],
+                        v: vec![(Value::from("bar"), Comparator::Equal)],
+                        t: vec![(span("1"), Comparator::Equal)],
+                        has: vec![],
                     }),
                     AstNode::Expression(Predicates {
-                        e: Some((1, Comparator::Equal)),
-                        a: Some(("foo".to_owned(), Comparator::Equal)),
-                        v: None,
-                        t: None,
+                        e: vec![(1, Comparator::Equal)],
+                        a: vec![("foo".to_owned(), Comparator::Equal)],
+                        v: vec![],
+                        t: vec![],
+                        has: vec![],
                     })];
 
         for (i, q) in qs.iter().enumerate() {
@@ -172,22 +601,25 @@ mod tests {
     fn parse_operators() {
         let qs = ["  a:foo e>=3 t<1 v<=bar ", "t>=1   e<1", "v:bar a>=foo "];
         let asts = [AstNode::Expression(Predicates {
-                        e: Some((3, Comparator::GreaterOrEqual)),
-                        a: Some(("foo".to_owned(), Comparator::Contains)),
-                        v: Some(("bar".to_owned(), Comparator::LessOrEqual)),
-                        t: Some((1, Comparator::Less)),
+                        e: vec![(3, Comparator::GreaterOrEqual)],
+                        a: vec![("foo".to_owned(), Comparator::Contains)],
+                        v: vec![(Value::from("bar"), Comparator::LessOrEqual)],
+                        t: vec![(span("1"), Comparator::Less)],
+                        has: vec![],
                     }),
                     AstNode::Expression(Predicates {
-                        e: Some((1, Comparator::Less)),
-                        a: None,
-                        v: None,
-                        t: Some((1, Comparator::GreaterOrEqual)),
+                        e: vec![(1, Comparator::Less)],
+                        a: vec![],
+                        v: vec![],
+                        t: vec![(span("1"), Comparator::GreaterOrEqual)],
+                        has: vec![],
                     }),
                     AstNode::Expression(Predicates {
-                        e: None,
-                        a: Some(("foo".to_owned(), Comparator::GreaterOrEqual)),
-                        v: Some(("bar".to_owned(), Comparator::Contains)),
-                        t: None,
+                        e: vec![],
+                        a: vec![("foo".to_owned(), Comparator::GreaterOrEqual)],
+                        v: vec![(Value::from("bar"), Comparator::Contains)],
+                        t: vec![],
+                        has: vec![],
                     })];
 
         for (i, q) in qs.iter().enumerate() {
@@ -195,16 +627,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_typed_values() {
+        let qs = ["v>100", "v<=2.5", "v=true", "v:Floyd", "v='02139'", "v=\"true\"",
+                  "v<2016-05-01"];
+        let vs = [(Value::Int(100), Comparator::Greater),
+                  (Value::Float(2.5), Comparator::LessOrEqual),
+                  (Value::Bool(true), Comparator::Equal),
+                  (Value::from("Floyd"), Comparator::Contains),
+                  (Value::from("02139"), Comparator::Equal),
+                  (Value::from("true"), Comparator::Equal),
+                  (Value::Timestamp(parse_time("2016-05-01").unwrap()), Comparator::Less)];
+
+        for (i, q) in qs.iter().enumerate() {
+            let preds = Predicates::new(vec![], vec![], vec![vs[i].clone()], vec![]);
+            assert_eq!(AstNode::Expression(preds), AstNode::parse(q).unwrap());
+        }
+    }
+
+    #[test]
+    fn compare_values() {
+        assert!(Comparator::Greater.test_value(&Value::Int(1000), &Value::Int(99)));
+        assert!(Comparator::Less.test_value(&Value::Float(99.5), &Value::Int(100)));
+        assert!(Comparator::LessOrEqual.test_value(&Value::Int(100), &Value::Int(100)));
+        assert!(Comparator::Contains.test_value(&Value::Int(1967), &Value::Int(96)));
+        assert!(!Comparator::Greater.test_value(&Value::from("abc"), &Value::Int(100)));
+    }
+
+    #[test]
+    fn compare_times() {
+        let (y1970, y1971) = (year(1970), year(1971));
+        assert!(Comparator::Equal.test_time(y1970 + 3600, &span("1970")));
+        assert!(!Comparator::Greater.test_time(y1970 + 3600, &span("1970")));
+        assert!(Comparator::Greater.test_time(y1971, &span("1970")));
+        assert!(Comparator::LessOrEqual.test_time(y1971 - 1, &span("1970")));
+        assert!(Comparator::Equal.test_time(1462105800, &span("2016-05-01")));
+        assert!(Comparator::Less.test_time(1462105799, &span("2016-05-01T12:30:00Z")));
+        assert_eq!("2016-05-01", span("2016-05-01").to_string());
+        assert_eq!("1970", span("1970").to_string());
+    }
+
+    #[test]
+    fn parse_unreadable_values() {
+        let qs = ["t=abc",
+                  "t='2016-05-01 12:30'",
+                  "a=foo t>2016-13-01",
+                  "t=2015-02-29",
+                  "e=abc",
+                  "e:(t=x)"];
+
+        for q in &qs {
+            assert!(AstNode::parse(q).is_err());
+        }
+    }
+
+    #[test]
+    fn display_predicates() {
+        let qs = ["e>1 a:track v='Pink Floyd' t<=1970", "v='02139' v=2139"];
+
+        for q in &qs {
+            match AstNode::parse(q).unwrap() {
+                AstNode::Expression(preds) => assert_eq!(*q, preds.to_string()),
+                ast => panic!("unexpected ast: {:?}", ast),
+            }
+        }
+    }
+
     #[test]
     fn parse_or() {
         let qs = ["e=1 | e=2", "e=1 a:foo   |  e=2"];
 
-        let e1 = Predicates::new(Some((1, Comparator::Equal)), None, None, None);
-        let e2 = Predicates::new(Some((2, Comparator::Equal)), None, None, None);
-        let e1afoo = Predicates::new(Some((1, Comparator::Equal)),
-                                     Some(("foo".to_owned(), Comparator::Contains)),
-                                     None,
-                                     None);
+        let e1 = Predicates::new(vec![(1, Comparator::Equal)], vec![], vec![], vec![]);
+        let e2 = Predicates::new(vec![(2, Comparator::Equal)], vec![], vec![], vec![]);
+        let e1afoo = Predicates::new(vec![(1, Comparator::Equal)],
+                                     vec![("foo".to_owned(), Comparator::Contains)],
+                                     vec![],
+                                     vec![]);
 
         let asts = [AstNode::Or(Box::new(AstNode::Expression(e1)),
                                 Box::new(AstNode::Expression(e2.clone()))),
@@ -220,19 +718,18 @@ mod tests {
     fn parse_joins() {
         let qs = ["e:(a=foo/bar v=baz)", " e:(e=1)  a:other t=1 "];
 
-        let e0 = Predicates::new(Some((0, Comparator::Contains)), None, None, None);
-        let e1 = Predicates::new(Some((1, Comparator::Equal)), None, None, None);
-        let e0aothert1 = Predicates::new(Some((0, Comparator::Contains)),
-                                         Some(("other".to_owned(), Comparator::Contains)),
-                                         None,
-                                         Some((1, Comparator::Equal)));
-        let afoovbaz = Predicates::new(None,
-                                       Some(("foo/bar".to_owned(), Comparator::Equal)),
-                                       Some(("baz".to_owned(), Comparator::Equal)),
-                                       None);
+        let e1 = Predicates::new(vec![(1, Comparator::Equal)], vec![], vec![], vec![]);
+        let aothert1 = Predicates::new(vec![],
+                                       vec![("other".to_owned(), Comparator::Contains)],
+                                       vec![],
+                                       vec![(1, Comparator::Equal)]);
+        let afoovbaz = Predicates::new(vec![],
+                                       vec![("foo/bar".to_owned(), Comparator::Equal)],
+                                       vec![(Value::from("baz"), Comparator::Equal)],
+                                       vec![]);
 
-        let asts = [AstNode::Join(e0, Box::new(AstNode::Expression(afoovbaz))),
-                    AstNode::Join(e0aothert1, Box::new(AstNode::Expression(e1)))];
+        let asts = [AstNode::Join(Predicates::empty(), Box::new(AstNode::Expression(afoovbaz))),
+                    AstNode::Join(aothert1, Box::new(AstNode::Expression(e1)))];
 
         for (i, q) in qs.iter().enumerate() {
             assert_eq!(asts[i], AstNode::parse(q).unwrap());
@@ -243,24 +740,313 @@ mod tests {
     fn parse_nested_joins() {
         let q = "e:(e:(a=foo/bar v=baz) a:inside t>10) a:other";
 
-        let e0aother = Predicates::new(Some((0, Comparator::Contains)),
-                                       Some(("other".to_owned(), Comparator::Contains)),
-                                       None,
-                                       None);
-        let e0ainsidet10 = Predicates::new(Some((0, Comparator::Contains)),
-                                           Some(("inside".to_owned(), Comparator::Contains)),
-                                           None,
-                                           Some((10, Comparator::Greater)));
-        let afoovbaz = Predicates::new(None,
-                                       Some(("foo/bar".to_owned(), Comparator::Equal)),
-                                       Some(("baz".to_owned(), Comparator::Equal)),
-                                       None);
-
-
-        let ast = AstNode::Join(e0aother,
-                                Box::new(AstNode::Join(e0ainsidet10,
+        let aother = Predicates::new(vec![],
+                                     vec![("other".to_owned(), Comparator::Contains)],
+                                     vec![],
+                                     vec![]);
+        let ainsidet10 = Predicates::new(vec![],
+                                         vec![("inside".to_owned(), Comparator::Contains)],
+                                         vec![],
+                                         vec![(10, Comparator::Greater)]);
+        let afoovbaz = Predicates::new(vec![],
+                                       vec![("foo/bar".to_owned(), Comparator::Equal)],
+                                       vec![(Value::from("baz"), Comparator::Equal)],
+                                       vec![]);
+
+
+        let ast = AstNode::Join(aother,
+                                Box::new(AstNode::Join(ainsidet10,
                                                        Box::new(AstNode::Expression(afoovbaz)))));
 
         assert_eq!(ast, AstNode::parse(q).unwrap());
     }
+
+    #[test]
+    fn parse_and() {
+        let qs = ["e=1 & a:foo", "(e=1) a:foo", "e=1 & (a:foo | v=bar)"];
+
+        let e1 = Predicates::new(vec![(1, Comparator::Equal)], vec![], vec![], vec![]);
+        let afoo = Predicates::new(vec![],
+                                   vec![("foo".to_owned(), Comparator::Contains)],
+                                   vec![],
+                                   vec![]);
+        let vbar = Predicates::new(vec![],
+                                   vec![],
+                                   vec![(Value::from("bar"), Comparator::Equal)],
+                                   vec![]);
+        let e1afoo = Predicates::new(vec![(1, Comparator::Equal)],
+                                     vec![("foo".to_owned(), Comparator::Contains)],
+                                     vec![],
+                                     vec![]);
+
+        let asts = [AstNode::Expression(e1afoo),
+                    AstNode::And(Box::new(AstNode::Expression(afoo.clone())),
+                                 Box::new(AstNode::Expression(e1.clone()))),
+                    AstNode::And(Box::new(AstNode::Expression(e1)),
+                                 Box::new(AstNode::Or(Box::new(AstNode::Expression(afoo)),
+                                                      Box::new(AstNode::Expression(vbar)))))];
+
+        for (i, q) in qs.iter().enumerate() {
+            assert_eq!(asts[i], AstNode::parse(q).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_not() {
+        let qs = ["a=track/artist !(v='Pink Floyd')", "a=track/artist not v='Pink Floyd'"];
+
+        let artist = Predicates::new(vec![],
+                                     vec![("track/artist".to_owned(), Comparator::Equal)],
+                                     vec![],
+                                     vec![]);
+        let floyd = Predicates::new(vec![],
+                                    vec![],
+                                    vec![(Value::from("Pink Floyd"), Comparator::Equal)],
+                                    vec![]);
+
+        let ast = AstNode::And(Box::new(AstNode::Expression(artist)),
+                               Box::new(AstNode::Not(Box::new(AstNode::Expression(floyd)))));
+
+        for q in &qs {
+            assert_eq!(ast, AstNode::parse(q).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_presence() {
+        let q = "a:gdp has(gdp/value) !has(gdp/country)";
+
+        let mut value = Predicates::new(vec![],
+                                        vec![("gdp".to_owned(), Comparator::Contains)],
+                                        vec![],
+                                        vec![]);
+        value.has.push("gdp/value".to_owned());
+        let mut country = Predicates::empty();
+        country.has.push("gdp/country".to_owned());
+        assert_eq!("has(gdp/country)", country.to_string());
+
+        let ast = AstNode::And(Box::new(AstNode::Expression(value)),
+                               Box::new(AstNode::Not(Box::new(AstNode::Expression(country)))));
+        assert_eq!(ast, AstNode::parse(q).unwrap());
+    }
+
+    #[test]
+    fn parse_grouped_or() {
+        let q = "(e=1 | e=2) t>1970";
+
+        let e1 = Predicates::new(vec![(1, Comparator::Equal)], vec![], vec![], vec![]);
+        let e2 = Predicates::new(vec![(2, Comparator::Equal)], vec![], vec![], vec![]);
+        let t1970 = Predicates::new(vec![],
+                                    vec![],
+                                    vec![],
+                                    vec![(span("1970"), Comparator::Greater)]);
+
+        let ast = AstNode::And(Box::new(AstNode::Expression(t1970)),
+                               Box::new(AstNode::Or(Box::new(AstNode::Expression(e1)),
+                                                    Box::new(AstNode::Expression(e2)))));
+
+        assert_eq!(ast, AstNode::parse(q).unwrap());
+    }
+
+    #[test]
+    fn parse_repeated_fields() {
+        let qs = ["t>=1970 t<1980", "e>1 a:track e<=10 v>=100 v<200"];
+
+        let asts = [AstNode::Expression(Predicates::new(vec![],
+                                                        vec![],
+                                                        vec![],
+                                                        vec![(span("1970"),
+                                                              Comparator::GreaterOrEqual),
+                                                             (span("1980"), Comparator::Less)])),
+                    AstNode::Expression(Predicates::new(vec![(1, Comparator::Greater),
+                                                             (10, Comparator::LessOrEqual)],
+                                                        vec![("track".to_owned(),
+                                                              Comparator::Contains)],
+                                                        vec![(Value::Int(100),
+                                                              Comparator::GreaterOrEqual),
+                                                             (Value::Int(200), Comparator::Less)],
+                                                        vec![]))];
+
+        for (i, q) in qs.iter().enumerate() {
+            assert_eq!(asts[i], AstNode::parse(q).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_repeated_joins() {
+        let q = "e:(a=album/name) e:(a=artist/name) a=track/name";
+
+        let atrack = Predicates::new(vec![],
+                                     vec![("track/name".to_owned(), Comparator::Equal)],
+                                     vec![],
+                                     vec![]);
+        let aalbum = Predicates::new(vec![],
+                                     vec![("album/name".to_owned(), Comparator::Equal)],
+                                     vec![],
+                                     vec![]);
+        let aartist = Predicates::new(vec![],
+                                      vec![("artist/name".to_owned(), Comparator::Equal)],
+                                      vec![],
+                                      vec![]);
+
+        let ast = AstNode::And(Box::new(AstNode::Join(atrack,
+                                                      Box::new(AstNode::Expression(aalbum)))),
+                               Box::new(AstNode::Join(Predicates::empty(),
+                                                      Box::new(AstNode::Expression(aartist)))));
+
+        assert_eq!(ast, AstNode::parse(q).unwrap());
+    }
+
+    #[test]
+    fn parse_ref_joins() {
+        let qs = ["track/album:(a=album/name v:Houses) a=track/name",
+                  "track/_album:(v=Flaming)"];
+
+        let atrack = Predicates::new(vec![],
+                                     vec![("track/name".to_owned(), Comparator::Equal)],
+                                     vec![],
+                                     vec![]);
+        let houses = Predicates::new(vec![],
+                                     vec![("album/name".to_owned(), Comparator::Equal)],
+                                     vec![(Value::from("Houses"), Comparator::Contains)],
+                                     vec![]);
+        let flaming = Predicates::new(vec![],
+                                      vec![],
+                                      vec![(Value::from("Flaming"), Comparator::Equal)],
+                                      vec![]);
+
+        let asts = [AstNode::RefJoin(atrack,
+                                     RefPath::new("track/album", false),
+                                     Box::new(AstNode::Expression(houses))),
+                    AstNode::RefJoin(Predicates::empty(),
+                                     RefPath::new("track/album", true),
+                                     Box::new(AstNode::Expression(flaming)))];
+
+        for (i, q) in qs.iter().enumerate() {
+            assert_eq!(asts[i], AstNode::parse(q).unwrap());
+        }
+    }
+
+    #[test]
+    fn display_ref_path() {
+        assert_eq!("track/_album", RefPath::parse("track/_album").to_string());
+        assert_eq!("track/album", RefPath::parse("track/album").to_string());
+    }
+
+    #[test]
+    fn parse_projection() {
+        let q = "a=track/artist v:Led => track/name  track/album.album/name";
+
+        let ast = AstNode::parse("a=track/artist v:Led").unwrap();
+        let name = Column {
+            refs: vec![],
+            attribute: "track/name".to_owned(),
+        };
+        let album = Column {
+            refs: vec![RefPath::new("track/album", false)],
+            attribute: "album/name".to_owned(),
+        };
+        let columns = vec![Selection::Field(Field::Attribute(name)),
+                           Selection::Field(Field::Attribute(album))];
+
+        assert_eq!(Query::new(ast, columns, vec![]), Query::parse(q).unwrap());
+        assert_eq!(Query::new(AstNode::True, vec![], vec![]), Query::parse(" ").unwrap());
+    }
+
+    #[test]
+    fn parse_aggregates() {
+        let q = "a=gdp/value => sum(v) max( v ) t by t";
+        let ast = AstNode::parse("a=gdp/value").unwrap();
+        let selections = vec![Selection::Aggregate(Aggregate::Sum, Field::V),
+                              Selection::Aggregate(Aggregate::Max, Field::V),
+                              Selection::Field(Field::T)];
+        assert_eq!(Query::new(ast, selections, vec![Field::T]), Query::parse(q).unwrap());
+
+        let q = "a=track/name => count(e) by track/album.album/name";
+        let album = Column {
+            refs: vec![RefPath::new("track/album", false)],
+            attribute: "album/name".to_owned(),
+        };
+        let query = Query::parse(q).unwrap();
+        assert_eq!(vec![Selection::Aggregate(Aggregate::Count, Field::E)], query.projection);
+        assert_eq!(vec![Field::Attribute(album)], query.group_by);
+    }
+
+    #[test]
+    fn parse_order_limit_offset() {
+        let query = Query::parse("a=gdp/value order by v desc, t limit 10 offset 20").unwrap();
+        assert_eq!(AstNode::parse("a=gdp/value").unwrap(), query.ast);
+        assert_eq!(vec![Order::new(Field::V, Direction::Descending),
+                        Order::new(Field::T, Direction::Ascending)],
+                   query.order);
+        assert_eq!(Some(10), query.limit);
+        assert_eq!(20, query.offset);
+
+        let query = Query::parse("a:track => count(e) by t order by t limit 5").unwrap();
+        assert_eq!(vec![Field::T], query.group_by);
+        assert_eq!(vec![Order::new(Field::T, Direction::Ascending)], query.order);
+        assert_eq!(Some(5), query.limit);
+
+        let query = Query::parse("offset 3").unwrap();
+        assert_eq!(AstNode::True, query.ast);
+        assert_eq!(3, query.offset);
+    }
+
+    #[test]
+    fn parse_tx_field() {
+        let query = Query::parse("a=track/name history => v tx t order by tx desc").unwrap();
+        assert_eq!(vec![Selection::Field(Field::V),
+                        Selection::Field(Field::Tx),
+                        Selection::Field(Field::T)],
+                   query.projection);
+        assert_eq!(vec![Order::new(Field::Tx, Direction::Descending)], query.order);
+    }
+
+    #[test]
+    fn parse_history() {
+        assert_eq!(Basis::Current, Query::parse("e=1 => v").unwrap().basis);
+
+        let query = Query::parse("e=1 history => v order by t").unwrap();
+        assert_eq!(AstNode::parse("e=1").unwrap(), query.ast);
+        assert_eq!(Basis::History, query.basis);
+        assert_eq!(1, query.projection.len());
+    }
+
+    #[test]
+    fn parse_as_of_since() {
+        let query = Query::parse("a=album/name as-of 3").unwrap();
+        assert_eq!(AstNode::parse("a=album/name").unwrap(), query.ast);
+        assert_eq!(Basis::AsOf(Moment::Tx(3)), query.basis);
+
+        let query = Query::parse("since 2016-05-01T12:30:00Z => e").unwrap();
+        assert_eq!(Basis::Since(Moment::Time(1462105800)), query.basis);
+
+        assert_eq!(Some(1462060800), parse_time("2016-05-01"));
+        assert!(Query::parse("as-of 2016-13-01").is_err());
+        assert!(Query::parse("as-of 2016-02-31").is_err());
+        assert!(Query::parse("as-of 2016-02-29").is_ok());
+    }
+
+    #[test]
+    fn sort_and_page() {
+        let datums = vec![Datum::new(1, "gdp/value", 3.5, year(2001)),
+                          Datum::new(2, "gdp/value", Value::Int(12), year(2001)),
+                          Datum::new(3, "gdp/value", 3.5, year(2000)),
+                          Datum::new(4, "gdp/value", "n/a", year(2002))];
+        let query = Query::parse("order by v desc, t limit 2 offset 1").unwrap();
+
+        let mut view = datums.iter().map(Row::from).collect::<Vec<Row>>();
+        query.sort(&mut view);
+        assert_eq!(vec![4, 2, 3, 1], view.iter().map(|d| d.e).collect::<Vec<usize>>());
+
+        let page = query.page(view);
+        assert_eq!(vec![2, 3], page.iter().map(|d| d.e).collect::<Vec<usize>>());
+
+        let qs = ["limit 99999999999999999999",
+                  "offset 99999999999999999999",
+                  "as-of 99999999999999999999"];
+        for q in &qs {
+            assert!(Query::parse(q).is_err());
+        }
+    }
 }
@@ -1,13 +1,69 @@
 use linenoise;
+use std::cmp;
+
+use csv_parser::Encoding;
+use data::TimeFormat;
+use schema::{Attribute, ValueType};
+use table::Table;
+
+const PAGE_SIZE: usize = 20;
 
 #[derive(Debug)]
 pub struct Join(pub String, pub String);
 
+/// Clauses of the `c` command following the time column.
+#[derive(Debug)]
+pub struct CsvOptions {
+    pub joins: Vec<Join>,
+    /// Column identifying the row's entity, rows matching an existing entity update it.
+    pub key: Option<String>,
+    /// Column types overriding the inferred ones.
+    pub types: Vec<(String, ValueType)>,
+    pub time_format: TimeFormat,
+    /// Skip rows that can't be imported rather than failing.
+    pub lenient: bool,
+    pub delimiter: u8,
+    pub quote: u8,
+    /// Lines starting with this are skipped
+    pub comment: Option<char>,
+    /// Column names of a file without a header row
+    pub headers: Option<Vec<String>>,
+    pub encoding: Encoding,
+    /// Keep null cells as explicit null values rather than leaving the attribute out.
+    pub keep_nulls: bool,
+    /// Cells read as null, besides empty ones
+    pub nulls: Vec<String>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            joins: vec![],
+            key: None,
+            types: vec![],
+            time_format: TimeFormat::default(),
+            lenient: false,
+            delimiter: b',',
+            quote: b'"',
+            comment: None,
+            headers: None,
+            encoding: Encoding::default(),
+            keep_nulls: false,
+            nulls: vec![],
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum CliCommand {
     Load(String),
-    LoadCsv(String, String, String, Vec<Join>),
+    LoadCsv(String, String, String, CsvOptions, Option<String>),
     Query(String),
+    Explain(String),
+    Analyze(String),
+    Retract(String, Option<String>),
+    Schema,
+    Declare(Attribute),
     Write(String),
     Empty,
     None,
@@ -19,6 +75,7 @@ pub enum CliError {
     UnknownCommand(String),
     InvalidArgs(String),
     InvalidJoinClause(String),
+    InvalidClause(String),
 }
 
 pub fn read() -> Result<CliCommand, CliError> {
@@ -44,15 +101,31 @@ pub fn read() -> Result<CliCommand, CliError> {
         "l" => Ok(CliCommand::Load(all_args)),
         "c" => {
             if args.len() >= 3 {
+                let (clauses, note) = parse_note(&args[3..].join(" "));
                 Ok(CliCommand::LoadCsv((*args.get(0).unwrap()).to_owned(),
                                        (*args.get(1).unwrap()).to_owned(),
                                        (*args.get(2).unwrap()).to_owned(),
-                                       try!(parse_joins(&args[3..].join(" ")))))
+                                       try!(parse_options(&clauses)),
+                                       note))
             } else {
                 Err(CliError::InvalidArgs(all_args))
             }
         }
         "q" => Ok(CliCommand::Query(all_args)),
+        "x" => Ok(CliCommand::Explain(all_args)),
+        "xa" | "analyze" => Ok(CliCommand::Analyze(all_args)),
+        "r" => {
+            let (query, note) = parse_note(&all_args);
+            Ok(CliCommand::Retract(query, note))
+        }
+        "schema" if args.is_empty() => Ok(CliCommand::Schema),
+        "schema" => {
+            let (declaration, doc) = parse_doc(&all_args);
+            match Attribute::parse(&declaration.split_whitespace().collect::<Vec<&str>>(), doc) {
+                Some(attribute) => Ok(CliCommand::Declare(attribute)),
+                None => Err(CliError::InvalidArgs(all_args)),
+            }
+        }
         "w" => Ok(CliCommand::Write(all_args)),
         "empty" => Ok(CliCommand::Empty),
         "clear" => {
@@ -64,23 +137,213 @@ pub fn read() -> Result<CliCommand, CliError> {
     }
 }
 
+/// Prints `text` a page at a time, waiting for enter between pages, "q" stops paging.
+pub fn page(text: &str) {
+    let lines = text.lines().collect::<Vec<&str>>();
+    print_pages(&pages(&[], &lines), lines.len());
+}
+
+/// Pages the rows of `table` like `page`, every page starts with the table's header.
+pub fn page_table(table: &Table) {
+    let text = table.to_string();
+    let lines = text.lines().collect::<Vec<&str>>();
+    // The column names and the rule under them
+    let (header, rows) = lines.split_at(cmp::min(2, lines.len()));
+    print_pages(&pages(header, rows), rows.len());
+}
+
+// Splits `lines` into pages of PAGE_SIZE lines after the header, a header is shown even
+// without any lines
+fn pages<'a>(header: &[&'a str], lines: &[&'a str]) -> Vec<Vec<&'a str>> {
+    if lines.is_empty() {
+        return if header.is_empty() { vec![] } else { vec![header.to_vec()] };
+    }
+    lines.chunks(PAGE_SIZE)
+         .map(|chunk| header.iter().chain(chunk).cloned().collect())
+         .collect()
+}
+
+fn print_pages(pages: &[Vec<&str>], total: usize) {
+    for (idx, page) in pages.iter().enumerate() {
+        if idx > 0 {
+            let prompt = format!("-- {}/{} more, q to stop -- ", idx * PAGE_SIZE, total);
+            match linenoise::input(&prompt) {
+                Some(ref i) if i == "q" => return,
+                None => return,
+                _ => {}
+            }
+        }
+        for line in page {
+            println!("{}", line);
+        }
+    }
+}
+
+// Splits a trailing note("...") clause, recorded on the transaction, from the rest of `raw`
+fn parse_note(raw: &str) -> (String, Option<String>) {
+    let note_re = regex!(r#"\s*note\("(.*)"\)\s*$"#);
+
+    match note_re.captures(raw) {
+        Some(caps) => {
+            let (start, _) = caps.pos(0).unwrap();
+            (raw[..start].to_owned(), Some(caps.at(1).unwrap().to_owned()))
+        }
+        None => (raw.to_owned(), None),
+    }
+}
+
+// Splits a trailing doc("...") clause, describing a declared attribute, from the rest of `raw`
+fn parse_doc(raw: &str) -> (String, Option<String>) {
+    let doc_re = regex!(r#"\s*doc\("(.*)"\)\s*$"#);
+
+    match doc_re.captures(raw) {
+        Some(caps) => {
+            let (start, _) = caps.pos(0).unwrap();
+            (raw[..start].to_owned(), Some(caps.at(1).unwrap().to_owned()))
+        }
+        None => (raw.to_owned(), None),
+    }
+}
+
+// schema artist/name str one identity doc("The name an artist records under")
 // c data/artists.csv artist Year
 // c data/albums.csv album Year join(Artist, "a=artist/name")
 // c data/tracks.csv track Year join(Artist, "a=artist/name") join(Album, "a=album/name")
+// c data/tracks.csv track Year note("tracks from the 2016 export")
+// c data/artists.csv artist Year key(Name)
+// c data/gdp.csv gdp Year type(Country Code, str) type(Value, float)
+// c data/events.csv event Date time(%d/%m/%Y)
+// c data/countries.csv country -
+// c data/gdp.csv gdp Year errors(skip)
+// c data/gdp.tsv gdp Year delimiter(tab) comment(#)
+// c data/exports.csv export Jahr delimiter(;) encoding(latin1)
+// c data/artists.csv artist Year headers(Name, Year)
+
+// A single ASCII character given in a clause, "tab" and "space" name the invisible ones
+fn byte(raw: &str) -> Option<u8> {
+    match raw {
+        "tab" => Some(b'\t'),
+        "space" => Some(b' '),
+        // Characters of a single byte are ASCII
+        _ if raw.len() == 1 => Some(raw.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+fn parse_options(raw: &str) -> Result<CsvOptions, CliError> {
+    let clause_re = regex!(r#"(\w+)\(((?:"[^"]*"|[^"()])*)\)"#);
+    let join_re = regex!(r#"^(\S+),\s*"(.*)"$"#);
+
+    let rest = clause_re.replace_all(raw, "");
+    if rest.trim() != "" {
+        return Err(CliError::InvalidClause(rest.trim().to_owned()));
+    }
 
-fn parse_joins(raw: &str) -> Result<Vec<Join>, CliError> {
-    let join_re = regex!(r#"(\S+),\s+"(.*)"\)"#);
-
-    raw.split("join(")
-       .map(|s| s.trim())
-       .filter(|s| *s != "")
-       .map(|clause| {
-           if let Some(caps) = join_re.captures(clause) {
-               Ok(Join(caps.at(1).unwrap().to_owned(),
-                       caps.at(2).unwrap().to_owned()))
-           } else {
-               Err(CliError::InvalidJoinClause(clause.to_owned()))
-           }
-       })
-       .collect()
+    let mut options = CsvOptions::default();
+    for caps in clause_re.captures_iter(raw) {
+        let (name, args) = (caps.at(1).unwrap(), caps.at(2).unwrap().trim());
+        match name {
+            "join" => {
+                match join_re.captures(args) {
+                    Some(join) => {
+                        options.joins.push(Join(join.at(1).unwrap().to_owned(),
+                                                join.at(2).unwrap().to_owned()))
+                    }
+                    None => return Err(CliError::InvalidJoinClause(args.to_owned())),
+                }
+            }
+            "key" if args != "" => options.key = Some(args.to_owned()),
+            "time" => {
+                match TimeFormat::parse(args) {
+                    Some(format) => options.time_format = format,
+                    None => return Err(CliError::InvalidClause(caps.at(0).unwrap().to_owned())),
+                }
+            }
+            "delimiter" if byte(args).is_some() => options.delimiter = byte(args).unwrap(),
+            "quote" if byte(args).is_some() => options.quote = byte(args).unwrap(),
+            "comment" if byte(args).is_some() => options.comment = byte(args).map(|b| b as char),
+            "headers" if args != "" => {
+                options.headers = Some(args.split(',').map(|h| h.trim().to_owned()).collect())
+            }
+            "encoding" if Encoding::parse(args).is_some() => {
+                options.encoding = Encoding::parse(args).unwrap()
+            }
+            "nulls" => {
+                for token in args.split(',').map(str::trim) {
+                    match token {
+                        "skip" => options.keep_nulls = false,
+                        "keep" => options.keep_nulls = true,
+                        "" => (),
+                        _ => options.nulls.push(token.to_owned()),
+                    }
+                }
+            }
+            "errors" if args == "skip" => options.lenient = true,
+            "errors" if args == "fail" => options.lenient = false,
+            "type" => {
+                match args.rfind(',').map(|idx| (&args[..idx], args[idx + 1..].trim())) {
+                    Some((column, value_type)) if ValueType::parse(value_type).is_some() => {
+                        options.types
+                               .push((column.trim().to_owned(),
+                                      ValueType::parse(value_type).unwrap()))
+                    }
+                    _ => return Err(CliError::InvalidClause(caps.at(0).unwrap().to_owned())),
+                }
+            }
+            _ => return Err(CliError::InvalidClause(caps.at(0).unwrap().to_owned())),
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pages, parse_options, CliError, PAGE_SIZE};
+    use csv_parser::Encoding;
+
+    #[test]
+    fn parse_key_and_join() {
+        let options = parse_options(r#"join(Artist, "a=artist/name") key(Name)"#).unwrap();
+        assert_eq!(Some("Name".to_owned()), options.key);
+        assert_eq!(1, options.joins.len());
+        assert_eq!("Artist", options.joins[0].0);
+        assert_eq!("a=artist/name", options.joins[0].1);
+    }
+
+    #[test]
+    fn parse_invalid_clauses() {
+        match parse_options("key(Name) upsert") {
+            Err(CliError::InvalidClause(ref rest)) if rest == "upsert" => (),
+            other => panic!("expected an invalid clause, got {:?}", other),
+        }
+        match parse_options("key()") {
+            Err(CliError::InvalidClause(ref clause)) if clause == "key()" => (),
+            other => panic!("expected an invalid clause, got {:?}", other),
+        }
+        match parse_options("join(Artist, a=artist/name)") {
+            Err(CliError::InvalidJoinClause(_)) => (),
+            other => panic!("expected an invalid join, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_file_options() {
+        let options = parse_options("headers(Name, Year) comment(#) encoding(utf-16)").unwrap();
+        assert_eq!(Some(vec!["Name".to_owned(), "Year".to_owned()]), options.headers);
+        assert_eq!(Some('#'), options.comment);
+        assert_eq!(Encoding::Utf16, options.encoding);
+    }
+
+    #[test]
+    fn pages_repeat_header() {
+        let lines = (0..PAGE_SIZE + 1).map(|i| i.to_string()).collect::<Vec<String>>();
+        let lines = lines.iter().map(|l| &l[..]).collect::<Vec<&str>>();
+
+        let paged = pages(&["e", "-"], &lines);
+        assert_eq!(2, paged.len());
+        assert_eq!(vec!["e", "-", "0"], paged[0][..3].to_vec());
+        assert_eq!(vec!["e", "-", "20"], paged[1]);
+        assert_eq!(PAGE_SIZE, pages(&[], &lines)[0].len());
+        assert_eq!(vec![vec!["e", "-"]], pages(&["e", "-"], &[]));
+    }
 }
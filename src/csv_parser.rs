@@ -1,66 +1,451 @@
 use csv;
 use scoped_threadpool::Pool;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use time;
 
 use ast::AstNode;
-use cli::Join;
-use data::{Datum, Db, Ref, Error};
+use cli::CsvOptions;
+use data::{Basis, Datum, Db, Ref, Error, Value};
 use filter::Filter;
+use schema::{Unique, ValueType};
+use storage::Row;
+
+/// Rows read to infer the type of each column.
+const SAMPLE_SIZE: usize = 1000;
+
+/// The cells of a column that don't convert to the column's type.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub column: String,
+    pub value_type: ValueType,
+    pub cells: usize,
+    /// The line and text of the first such cell
+    pub first: (usize, String),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cells = if self.cells == 1 { "cell isn't" } else { "cells aren't" };
+        write!(f,
+               "column {}: {} {} a {}, the first on line {}: {:?}",
+               self.column,
+               self.cells,
+               cells,
+               self.value_type,
+               self.first.0,
+               self.first.1)
+    }
+}
+
+/// The text encoding of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    /// Little endian unless the file starts with a big endian byte order mark
+    Utf16,
+}
+
+impl Encoding {
+    pub fn parse(raw: &str) -> Option<Encoding> {
+        match &*raw.to_lowercase() {
+            "utf8" | "utf-8" => Some(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "utf16" | "utf-16" => Some(Encoding::Utf16),
+            _ => None,
+        }
+    }
+
+    /// Decodes `bytes` without a byte order mark, `None` if they aren't valid.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match *self {
+            Encoding::Utf8 => {
+                String::from_utf8(bytes.to_vec())
+                    .ok()
+                    .map(|text| text.trim_left_matches('\u{feff}').to_owned())
+            }
+            // Latin-1 bytes are the first 256 code points
+            Encoding::Latin1 => Some(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Utf16 => {
+                if bytes.len() % 2 != 0 {
+                    return None;
+                }
+                let big_endian = bytes.starts_with(&[0xfe, 0xff]);
+                let units = bytes.chunks(2)
+                                 .map(|pair| {
+                                     if big_endian {
+                                         (pair[0] as u16) << 8 | pair[1] as u16
+                                     } else {
+                                         (pair[1] as u16) << 8 | pair[0] as u16
+                                     }
+                                 })
+                                 .collect::<Vec<u16>>();
+                String::from_utf16(&units)
+                    .ok()
+                    .map(|text| text.trim_left_matches('\u{feff}').to_owned())
+            }
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Utf8
+    }
+}
+
+/// A row that couldn't be imported, lines count from 1 and the header is the first.
+#[derive(Debug)]
+pub struct RowError {
+    pub line: usize,
+    pub column: Option<String>,
+    pub reason: String,
+}
+
+impl RowError {
+    pub fn new(line: usize, column: Option<&str>, reason: String) -> RowError {
+        RowError {
+            line: line,
+            column: column.map(|c| c.to_owned()),
+            reason: reason,
+        }
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "line {}", self.line));
+        if let Some(ref column) = self.column {
+            try!(write!(f, ", column {}", column));
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// The datums and refs parsed from a file, with the rows skipped along the way.
+#[derive(Debug)]
+pub struct Import {
+    pub datums: Vec<Datum>,
+    pub refs: Vec<Ref>,
+    /// Entity ids allocated to new entities
+    pub offset: usize,
+    pub read: usize,
+    pub skipped: Vec<RowError>,
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "read: {}, imported: {}, skipped: {}",
+               self.read,
+               self.read - self.skipped.len(),
+               self.skipped.len())
+    }
+}
 
 #[derive(Debug)]
 pub struct CsvParser<'a> {
     filename: &'a str,
     entity: &'a str,
     time: &'a str,
-    joins: &'a [Join],
+    options: &'a CsvOptions,
 }
 
 impl<'a> CsvParser<'a> {
-    pub fn new(filename: &'a str, entity: &'a str, time: &'a str, joins: &'a [Join]) -> CsvParser<'a> {
+    pub fn new(filename: &'a str, entity: &'a str, time: &'a str, options: &'a CsvOptions)
+               -> CsvParser<'a> {
         CsvParser {
             filename: filename,
             entity: entity,
             time: time,
-            joins: joins,
+            options: options,
         }
     }
 
-    pub fn parse(self, db: &Db, pool: &mut Pool) -> Result<(Vec<Datum>, Vec<Ref>, usize), Error> {
-        let mut rdr = try!(csv::Reader::from_file(self.filename));
-        let headers = rdr.headers().expect("headers required to convert CSV");
+    /// Parses every row of the file. Bad rows fail the import unless it's lenient, then they
+    /// are skipped.
+    pub fn parse(self, db: &Db, pool: &mut Pool) -> Result<Import, Error> {
+        let (text, lines) = try!(self.read());
+        let mut rdr = csv::Reader::from_string(text)
+                          .delimiter(self.options.delimiter)
+                          .quote(self.options.quote)
+                          .has_headers(self.options.headers.is_none());
+        let (headers, first) = match self.options.headers {
+            Some(ref headers) => (headers.clone(), 0),
+            None => (try!(rdr.headers()), 1),
+        };
 
-        let time_index = match headers.iter()
-                                      .enumerate()
-                                      .find(|&(_, h)| h == self.time) {
-            Some((idx, _)) => idx,
+        // Without a time column, "-", every datum is given the time of the import
+        let time_index = match headers.iter().position(|h| h == self.time) {
+            Some(idx) => Some(idx),
+            None if self.time == "-" => None,
             None => return Err(Error::MissingTimeHeader(self.time.to_owned())),
         };
+        let now = time::get_time().sec;
+
+        let mut records = vec![];
+        let mut skipped = vec![];
+        for (idx, record) in rdr.records().enumerate() {
+            let line = lines.get(first + idx).cloned().unwrap_or(0);
+            match record {
+                Ok(ref row) if row.len() != headers.len() => {
+                    let reason = format!("{} cells for {} columns", row.len(), headers.len());
+                    try!(self.skip(&mut skipped, RowError::new(line, None, reason)))
+                }
+                Ok(row) => records.push((line, row)),
+                Err(csv::Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => {
+                    try!(self.skip(&mut skipped, RowError::new(line, None, err.to_string())))
+                }
+            }
+        }
+        let read = records.len() + skipped.len();
+
+        let types = try!(self.column_types(db, &headers, &records));
+        // Strict imports report every column that doesn't conform before failing, lenient ones
+        // skip the rows as they come
+        if !self.options.lenient {
+            let mismatches = self.mismatches(&headers, &types, &records, time_index);
+            if !mismatches.is_empty() {
+                return Err(Error::NonConforming(mismatches));
+            }
+        }
+
+        let key_index = try!(self.key_index(db, &headers));
+        let key_attribute = key_index.map(|idx| (self.attribute(&headers[idx]), types[idx]));
+        let mut keys = HashMap::new();
+        // Where each keyed entity's datums are, so later rows can replace them
+        let mut positions: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut replaced = HashSet::new();
 
         let mut eid = db.offset;
-        let datums_res = rdr.records()
-                            .map(|row_res| {
-                                let row = try!(row_res);
-                                eid += 1;
-                                let datums = try!(Self::parse_row(row,
-                                                                  &headers,
-                                                                  time_index,
-                                                                  eid,
-                                                                  self.entity));
-                                Ok(datums)
-                            })
-                            .collect::<Result<Vec<Vec<Datum>>, Error>>();
-
-        let datums = match datums_res {
-            Ok(d) => d.into_iter().flat_map(|v| v).collect::<Vec<Datum>>(),
-            Err(e) => return Err(e),
-        };
+        let mut datums = vec![];
+        for (line, row) in records {
+            // Rows with a null key can't match anything and are new entities
+            let key = key_index.and_then(|idx| {
+                if self.is_null(&row[idx]) {
+                    None
+                } else {
+                    Some(row[idx].clone())
+                }
+            });
+
+            // Rows with the key of an entity seen before, in the db or earlier in the file, are
+            // about that entity
+            let matched = key.as_ref().and_then(|k| {
+                keys.get(k).cloned().or_else(|| {
+                    key_attribute.as_ref().and_then(|&(ref a, value_type)| {
+                        value_type.convert(k).and_then(|v| Self::find_entity(db, a, &v))
+                    })
+                })
+            });
+            let row_eid = matched.unwrap_or(eid + 1);
+
+            let mut row_datums = match self.parse_row(line,
+                                                      row,
+                                                      &headers,
+                                                      &types,
+                                                      time_index,
+                                                      now,
+                                                      row_eid) {
+                Ok(row_datums) => row_datums,
+                Err(err) => {
+                    try!(self.skip(&mut skipped, err));
+                    continue;
+                }
+            };
+            if matched.is_none() {
+                eid += 1;
+            }
+            // A row about an entity of an earlier row replaces that row's values, the way
+            // importing it again would
+            if let Some(earlier) = positions.get(&row_eid) {
+                for &pos in earlier {
+                    if row_datums.iter().any(|d| d.a == datums[pos].a) {
+                        replaced.insert(pos);
+                    }
+                }
+            }
+            if row_eid <= db.offset {
+                row_datums.retain(|d| !Self::is_current(db, d));
+            }
+            if let Some(k) = key {
+                keys.insert(k, row_eid);
+                positions.entry(row_eid)
+                         .or_insert_with(Vec::new)
+                         .extend(datums.len()..datums.len() + row_datums.len());
+            }
+            datums.extend(row_datums);
+        }
+        let datums = datums.into_iter()
+                           .enumerate()
+                           .filter(|&(pos, _)| !replaced.contains(&pos))
+                           .map(|(_, d)| d)
+                           .collect::<Vec<Datum>>();
 
         let refs = self.find_refs(&datums, &db, pool);
-        Ok((datums, refs, eid))
+        skipped.sort_by(|l, r| l.line.cmp(&r.line));
+        Ok(Import {
+            datums: datums,
+            refs: refs,
+            offset: eid - db.offset,
+            read: read,
+            skipped: skipped,
+        })
+    }
+
+    // Decodes the file and drops its comment lines. Returns the text left with the number of
+    // the line each of its records starts on.
+    fn read(&self) -> Result<(String, Vec<usize>), Error> {
+        let mut bytes = vec![];
+        try!(try!(File::open(self.filename)).read_to_end(&mut bytes));
+        let decoded = match self.options.encoding.decode(&bytes) {
+            Some(decoded) => decoded,
+            None => return Err(Error::Undecodable(self.options.encoding)),
+        };
+
+        let quote = self.options.quote as char;
+        let mut text = String::with_capacity(decoded.len());
+        let mut lines = vec![];
+        let mut quoted = false;
+        for (idx, line) in decoded.lines().enumerate() {
+            // Lines inside a quoted cell continue its record, even those that look like comments
+            if !quoted {
+                if self.options.comment.map_or(false, |c| line.starts_with(c)) {
+                    continue;
+                }
+                lines.push(idx + 1);
+            }
+            text.push_str(line);
+            text.push('\n');
+            // Escaped quotes come in pairs and leave the cell open
+            if line.chars().filter(|&c| c == quote).count() % 2 == 1 {
+                quoted = !quoted;
+            }
+        }
+        Ok((text, lines))
+    }
+
+    fn skip(&self, skipped: &mut Vec<RowError>, err: RowError) -> Result<(), Error> {
+        if !self.options.lenient {
+            return Err(Error::Row(err));
+        }
+        skipped.push(err);
+        Ok(())
+    }
+
+    // The type of each column: given in a type(...) clause, the type of a declared attribute or
+    // the most specific type of the column's cells in the first rows
+    fn column_types(&self, db: &Db, headers: &[String], records: &[(usize, Vec<String>)])
+                    -> Result<Vec<ValueType>, Error> {
+        for &(ref column, _) in &self.options.types {
+            if !headers.contains(column) {
+                return Err(Error::MissingHeader(column.clone()));
+            }
+        }
+
+        let types = headers.iter()
+                           .enumerate()
+                           .map(|(idx, header)| {
+                               let given = self.options
+                                               .types
+                                               .iter()
+                                               .find(|&&(ref c, _)| c == header)
+                                               .map(|&(_, value_type)| value_type);
+                               let declared = db.schema
+                                                .get(&self.attribute(header))
+                                                .map(|a| a.value_type);
+                               given.or(declared).unwrap_or_else(|| {
+                                   ValueType::infer(records.iter()
+                                                           .take(SAMPLE_SIZE)
+                                                           .map(|&(_, ref r)| &r[idx][..])
+                                                           .filter(|c| !self.is_null(c)))
+                               })
+                           })
+                           .collect();
+        Ok(types)
+    }
+
+    fn mismatches(&self,
+                  headers: &[String],
+                  types: &[ValueType],
+                  records: &[(usize, Vec<String>)],
+                  time_index: Option<usize>)
+                  -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+
+        for (idx, (header, value_type)) in headers.iter().zip(types).enumerate() {
+            if Some(idx) == time_index {
+                continue;
+            }
+            let failed = records.iter()
+                                .map(|&(line, ref r)| (line, &r[idx]))
+                                .filter(|&(_, c)| {
+                                    !self.is_null(c) && value_type.convert(c).is_none()
+                                })
+                                .map(|(line, c)| (line, c.clone()))
+                                .collect::<Vec<(usize, String)>>();
+            if let Some(first) = failed.first() {
+                mismatches.push(Mismatch {
+                    column: header.clone(),
+                    value_type: *value_type,
+                    cells: failed.len(),
+                    first: first.clone(),
+                });
+            }
+        }
+        mismatches
+    }
+
+    // The index of the key column, either given or the first column of an identity attribute
+    fn key_index(&self, db: &Db, headers: &[String]) -> Result<Option<usize>, Error> {
+        match self.options.key {
+            Some(ref key) => {
+                match headers.iter().position(|h| h == key) {
+                    Some(idx) => Ok(Some(idx)),
+                    None => Err(Error::MissingHeader(key.clone())),
+                }
+            }
+            None => {
+                Ok(headers.iter().position(|h| {
+                    db.schema
+                      .get(&self.attribute(h))
+                      .map_or(false, |a| a.unique == Some(Unique::Identity))
+                }))
+            }
+        }
+    }
+
+    // Empty cells and the sentinels given in a nulls(...) clause
+    fn is_null(&self, cell: &str) -> bool {
+        cell.is_empty() || self.options.nulls.iter().any(|n| n == cell)
+    }
+
+    fn find_entity(db: &Db, attribute: &str, value: &Value) -> Option<usize> {
+        db.indexes
+          .holding(&db.datums, attribute, value)
+          .into_iter()
+          .find(|&p| db.visible(p, Basis::Current))
+          .map(|p| db.datums.fact(p).e)
+    }
+
+    fn is_current(db: &Db, datum: &Datum) -> bool {
+        db.indexes
+          .about(&db.datums, datum.e, &datum.a)
+          .into_iter()
+          .any(|p| db.visible(p, Basis::Current) && db.datums.row(p).same_fact(&Row::from(datum)))
+    }
+
+    fn attribute(&self, header: &str) -> String {
+        format!("{}/{}", self.entity, robotize(header))
     }
 
     fn find_refs(&self, datums: &[Datum], db: &Db, pool: &mut Pool) -> Vec<Ref> {
-        self.joins
+        self.options
+            .joins
             .iter()
             .flat_map(|join| {
                 let (column, query) = (&join.0, &join.1);
@@ -72,11 +457,11 @@ impl<'a> CsvParser<'a> {
 
     fn find_refs_for_join(&self, datums: &[Datum], column: &str, query: &str, filter: Filter)
                           -> Vec<Ref> {
-        let attribute = format!("{}/{}", self.entity, robotize(&column));
+        let attribute = self.attribute(column);
         let ast = AstNode::parse(&query).unwrap();
 
         let new_datums = datums.iter()
-                               .filter(|d| d.a == attribute)
+                               .filter(|d| d.a == attribute && d.v != Value::Null)
                                .cloned()
                                .collect::<Vec<Datum>>();
         let old_datums = filter.execute(&ast).datums;
@@ -90,10 +475,10 @@ impl<'a> CsvParser<'a> {
                   .collect()
     }
 
-    fn generate_refs(new: &Datum, index: &HashMap<&str, Vec<&Datum>>) -> Vec<Option<Ref>> {
+    fn generate_refs(new: &Datum, index: &HashMap<String, Vec<Row>>) -> Vec<Option<Ref>> {
         let new_entity = new.a.split('/').next().unwrap();
 
-        match index.get(new.v.as_str()) {
+        match index.get(&*new.v.as_text()) {
             Some(old_matches) => {
                 old_matches.iter()
                            .map(|old| {
@@ -109,24 +494,49 @@ impl<'a> CsvParser<'a> {
         }
     }
 
-    fn parse_row(row: Vec<String>, headers: &[String], time_index: usize, eid: usize, entity: &str)
-                 -> Result<Vec<Datum>, Error> {
-        let time = match row[time_index].parse::<usize>() {
-            Ok(t) => t,
-            Err(_) => return Err(Error::TimeColumnTypeError(row[time_index].to_owned())),
+    fn parse_row(&self,
+                 line: usize,
+                 row: Vec<String>,
+                 headers: &[String],
+                 types: &[ValueType],
+                 time_index: Option<usize>,
+                 now: i64,
+                 eid: usize)
+                 -> Result<Vec<Datum>, RowError> {
+        let time = match time_index {
+            Some(idx) => {
+                match self.options.time_format.read(&row[idx]) {
+                    Some(t) => t,
+                    None => {
+                        let reason = format!("{:?} isn't a time", row[idx]);
+                        return Err(RowError::new(line, Some(&headers[idx]), reason));
+                    }
+                }
+            }
+            None => now,
         };
-        let datums = headers.iter()
-                            .enumerate()
-                            .filter(|&(i, _)| i != time_index)
-                            .map(|(_, h)| h)
-                            .zip(row)
-                            .map(|(header, val)| {
-                                Datum::new(eid,
-                                           format!("{}/{}", entity, robotize(header)),
-                                           val,
-                                           time)
-                            })
-                            .collect();
+
+        let mut datums = vec![];
+        for (idx, ((header, value_type), val)) in headers.iter().zip(types).zip(row).enumerate() {
+            if Some(idx) == time_index {
+                continue;
+            }
+            // Null cells leave the attribute out unless they're kept as null values
+            if self.is_null(&val) {
+                if self.options.keep_nulls {
+                    datums.push(Datum::new(eid, self.attribute(header), Value::Null, time));
+                }
+                continue;
+            }
+            let value = match value_type.convert(&val) {
+                Some(value) => value,
+                None => {
+                    let reason = format!("{:?} isn't a {}", val, value_type);
+                    return Err(RowError::new(line, Some(header), reason));
+                }
+            };
+            datums.push(Datum::new(eid, self.attribute(header), value, time));
+        }
         Ok(datums)
     }
 }
@@ -136,12 +546,211 @@ fn robotize(string: &str) -> String {
           .to_lowercase()
 }
 
-fn index_by_value(datums: Vec<&Datum>) -> HashMap<&str, Vec<&Datum>> {
+fn index_by_value(datums: Vec<Row>) -> HashMap<String, Vec<Row>> {
     let mut index = HashMap::new();
     for datum in datums {
-        if let Some(mut foo) = index.insert(datum.v.as_str(), vec![datum]) {
-            foo.push(datum)
-        }
+        index.entry(datum.v.to_string()).or_insert_with(Vec::new).push(datum);
     }
     index
 }
+
+#[cfg(test)]
+mod tests {
+    use scoped_threadpool::Pool;
+    use std::fs::File;
+    use std::io::Write;
+
+    use super::{CsvParser, Encoding, Import};
+    use cli::{CsvOptions, Join};
+    use data::{Basis, Db, Error};
+    use schema::{Attribute, ValueType};
+    use temp_file::TempFile;
+
+    const ARTISTS: &'static str = "Name,Country,Year\nLed Zeppelin,UK,1968\nQueen,UK,1970\n";
+
+    const GDP: &'static str = "Country,Value,Year\nUK,2.5,2015\nUK,n/a,2016\nUK,2.9,2017\n";
+
+    fn parse(db: &Db, contents: &str, entity: &str, options: &CsvOptions)
+             -> Result<Import, Error> {
+        let file = TempFile::new(entity, "csv");
+        File::create(file.path()).unwrap().write_all(contents.as_bytes()).unwrap();
+        CsvParser::new(file.to_str(), entity, "Year", options).parse(db, &mut Pool::new(1))
+    }
+
+    // Parses and commits a file like the `c` command does
+    fn load(db: &mut Db, contents: &str, entity: &str, options: &CsvOptions) -> Import {
+        let import = parse(db, contents, entity, options).unwrap();
+        let commit = db.prepare_upsert(import.datums.clone(),
+                                       import.refs.clone(),
+                                       import.offset,
+                                       None,
+                                       None)
+                       .unwrap();
+        db.apply(commit);
+        import
+    }
+
+    fn keyed(key: &str) -> CsvOptions {
+        CsvOptions { key: Some(key.to_owned()), ..CsvOptions::default() }
+    }
+
+    fn current(db: &Db, eid: usize, attribute: &str) -> Vec<String> {
+        db.indexes
+          .about(&db.datums, eid, attribute)
+          .into_iter()
+          .filter(|&p| db.visible(p, Basis::Current))
+          .map(|p| db.datums.row(p).v.to_string())
+          .collect()
+    }
+
+    #[test]
+    fn upsert_on_key() {
+        let mut db = Db::new();
+        load(&mut db, ARTISTS, "artist", &CsvOptions::default());
+
+        let rows = "Name,Country,Year\nQueen,GB,1970\nABBA,SE,1972\nABBA,Sweden,1974\n";
+        let import = load(&mut db, rows, "artist", &keyed("Name"));
+        // Queen is in the db, the second ABBA row is about the entity of the first and replaces
+        // its values
+        assert_eq!(1, import.offset);
+        assert_eq!(vec![(2, "GB".to_owned()), (4, "Sweden".to_owned())],
+                   import.datums
+                         .iter()
+                         .filter(|d| d.a == "artist/country")
+                         .map(|d| (d.e, d.v.to_string()))
+                         .collect::<Vec<(usize, String)>>());
+        // Unchanged values aren't asserted again, changed ones replace the old
+        assert!(!import.datums.iter().any(|d| d.e == 2 && d.a == "artist/name"));
+        assert_eq!(vec!["GB"], current(&db, 2, "artist/country"));
+        assert_eq!(vec!["Queen"], current(&db, 2, "artist/name"));
+        assert_eq!(vec!["Sweden"], current(&db, 4, "artist/country"));
+        assert_eq!(vec!["1974"], current(&db, 4, "artist/year"));
+    }
+
+    #[test]
+    fn upsert_on_identity() {
+        let mut db = Db::new();
+        load(&mut db, ARTISTS, "artist", &CsvOptions::default());
+        let name = Attribute::parse(&["artist/name", "str", "one", "identity"], None).unwrap();
+        let commit = db.prepare_declare(name, None).unwrap();
+        db.apply(commit);
+
+        let rows = "Name,Country,Year\nQueen,GB,1970\n";
+        let import = parse(&db, rows, "artist", &CsvOptions::default()).unwrap();
+        assert_eq!(0, import.offset);
+        assert_eq!(vec![2], import.datums.iter().map(|d| d.e).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn missing_key_column() {
+        match parse(&Db::new(), ARTISTS, "artist", &keyed("Id")) {
+            Err(Error::MissingHeader(ref header)) if header == "Id" => (),
+            other => panic!("expected a missing header, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upsert_replaces_refs() {
+        let mut db = Db::new();
+        load(&mut db, ARTISTS, "artist", &CsvOptions::default());
+
+        let options = CsvOptions {
+            key: Some("Name".to_owned()),
+            joins: vec![Join("Artist".to_owned(), "a=artist/name".to_owned())],
+            ..CsvOptions::default()
+        };
+        load(&mut db, "Name,Artist,Year\nIV,Led Zeppelin,1971\n", "album", &options);
+        load(&mut db, "Name,Artist,Year\nIV,Queen,1971\n", "album", &options);
+
+        // Album 4 now only refers to Queen
+        assert_eq!(vec![(4, 2)],
+                   (0..db.refs.len())
+                       .filter(|&p| db.ref_visible(p, Basis::Current))
+                       .map(|p| (db.refs[p].e, db.refs[p].v))
+                       .collect::<Vec<(usize, usize)>>());
+    }
+
+    fn floats(lenient: bool) -> CsvOptions {
+        CsvOptions {
+            types: vec![("Value".to_owned(), ValueType::Float)],
+            lenient: lenient,
+            ..CsvOptions::default()
+        }
+    }
+
+    #[test]
+    fn strict_refuses_nonconforming() {
+        match parse(&Db::new(), GDP, "gdp", &floats(false)) {
+            Err(Error::NonConforming(ref mismatches)) => {
+                assert_eq!(1, mismatches.len());
+                assert_eq!("column Value: 1 cell isn't a float, the first on line 3: \"n/a\"",
+                           mismatches[0].to_string());
+            }
+            other => panic!("expected nonconforming columns, got {:?}", other),
+        }
+        match parse(&Db::new(), "Country,Value,Year\nUK,2.5\n", "gdp", &floats(false)) {
+            Err(Error::Row(ref err)) => assert_eq!(2, err.line),
+            other => panic!("expected a bad row, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lenient_skips_rows() {
+        let rows = "Country,Value,Year\nUK,2.5,2015\nUK,n/a,2016\nUK,2.9\nUK,3.1,never\n";
+        let import = parse(&Db::new(), rows, "gdp", &floats(true)).unwrap();
+        assert_eq!(2, import.datums.len());
+        assert_eq!("read: 4, imported: 1, skipped: 3", import.to_string());
+        assert_eq!(vec![(3, Some("Value".to_owned())), (4, None), (5, Some("Year".to_owned()))],
+                   import.skipped
+                         .iter()
+                         .map(|e| (e.line, e.column.clone()))
+                         .collect::<Vec<(usize, Option<String>)>>());
+        assert_eq!("line 3, column Value: \"n/a\" isn't a float",
+                   import.skipped[0].to_string());
+    }
+
+    #[test]
+    fn decode_encodings() {
+        assert_eq!(Some("Zürich".to_owned()), Encoding::Latin1.decode(b"Z\xfcrich"));
+        assert_eq!(None, Encoding::Utf8.decode(b"Z\xfcrich"));
+        assert_eq!(Some("Zürich".to_owned()),
+                   Encoding::Utf8.decode(b"\xef\xbb\xbfZ\xc3\xbcrich"));
+        // Little endian with or without a byte order mark, big endian only after one
+        assert_eq!(Some("Zü".to_owned()), Encoding::Utf16.decode(b"Z\x00\xfc\x00"));
+        assert_eq!(Some("Zü".to_owned()), Encoding::Utf16.decode(b"\xff\xfeZ\x00\xfc\x00"));
+        assert_eq!(Some("Zü".to_owned()), Encoding::Utf16.decode(b"\xfe\xff\x00Z\x00\xfc"));
+        assert_eq!(None, Encoding::Utf16.decode(b"Z\x00\xfc"));
+    }
+
+    #[test]
+    fn comments_and_lines() {
+        let options = CsvOptions { comment: Some('#'), lenient: true, ..CsvOptions::default() };
+        let rows = "# exported\nName,Notes,Year\nLed Zeppelin,\"London\n# not a comment\",1968\n\
+                    # another\nQueen,London,never\n";
+        let import = parse(&Db::new(), rows, "artist", &options).unwrap();
+        assert_eq!(vec!["London\n# not a comment".to_owned()],
+                   import.datums
+                         .iter()
+                         .filter(|d| d.a == "artist/notes")
+                         .map(|d| d.v.to_string())
+                         .collect::<Vec<String>>());
+        // Lines count from the top of the file, comments and all
+        assert_eq!(vec![6], import.skipped.iter().map(|e| e.line).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn given_headers() {
+        let options = CsvOptions {
+            headers: Some(vec!["Name".to_owned(), "Year".to_owned()]),
+            lenient: true,
+            ..CsvOptions::default()
+        };
+        let import = parse(&Db::new(), "Queen,1970\nABBA,never\n", "artist", &options).unwrap();
+        assert_eq!(vec![(1, "artist/name".to_owned(), "Queen".to_owned())],
+                   import.datums
+                         .iter()
+                         .map(|d| (d.e, d.a.clone(), d.v.to_string()))
+                         .collect::<Vec<(usize, String, String)>>());
+        assert_eq!(vec![2], import.skipped.iter().map(|e| e.line).collect::<Vec<usize>>());
+    }
+}
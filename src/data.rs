@@ -1,39 +1,310 @@
 use csv;
-use bincode;
-use bincode::SizeLimit;
 use bincode::rustc_serialize as serialize;
-use flate2::write::ZlibEncoder;
-use flate2::read::ZlibDecoder;
-use flate2::Compression;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path;
-use std::fs::File;
 use std::io;
+use time;
 
+use csv_parser::{Encoding, Mismatch, RowError};
+use format;
+use index::{Indexes, RefIndex};
+use schema;
+use schema::{Attribute, Cardinality, Schema};
+use storage::{Row, Storage};
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Timestamp(i64),
+    Ref(usize),
+    /// An explicitly missing value, like an empty CSV cell
+    Null,
+}
+
+impl Value {
+    /// Infer the most specific type for a raw string, falling back to `Str`.
+    pub fn parse(raw: &str) -> Value {
+        if let Ok(i) = raw.parse::<i64>() {
+            return Value::Int(i);
+        }
+        if is_numeric(raw) {
+            if let Ok(f) = raw.parse::<f64>() {
+                return Value::Float(f);
+            }
+        }
+        match raw {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::Str(raw.to_owned()),
+        }
+    }
+
+    /// Orders two values of compatible types, ints and floats compare numerically.
+    /// Values of unrelated types are not comparable.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (&Value::Int(l), &Value::Int(r)) => Some(l.cmp(&r)),
+            (&Value::Int(l), &Value::Float(r)) => (l as f64).partial_cmp(&r),
+            (&Value::Float(l), &Value::Int(r)) => l.partial_cmp(&(r as f64)),
+            (&Value::Float(l), &Value::Float(r)) => l.partial_cmp(&r),
+            (&Value::Bool(l), &Value::Bool(r)) => Some(l.cmp(&r)),
+            (&Value::Str(ref l), &Value::Str(ref r)) => Some(l.cmp(r)),
+            (&Value::Timestamp(l), &Value::Timestamp(r)) => Some(l.cmp(&r)),
+            (&Value::Ref(l), &Value::Ref(r)) => Some(l.cmp(&r)),
+            (&Value::Null, &Value::Null) => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+
+    /// A total order over all values used to keep indexes sorted, numbers sort together with NaN
+    /// after the others and other types are grouped by kind.
+    pub fn total_cmp(&self, other: &Value) -> Ordering {
+        match self.compare(other) {
+            Some(ord) => ord,
+            // Only NaN fails to compare with a value of its own kind
+            None if self.rank() == other.rank() => self.is_nan().cmp(&other.is_nan()),
+            None => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    fn is_nan(&self) -> bool {
+        match *self {
+            Value::Float(n) => n.is_nan(),
+            _ => false,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match *self {
+            Value::Bool(_) => 0,
+            Value::Int(_) | Value::Float(_) => 1,
+            Value::Timestamp(_) => 2,
+            Value::Str(_) => 3,
+            Value::Ref(_) => 4,
+            Value::Null => 5,
+        }
+    }
+
+    pub fn as_text(&self) -> Cow<str> {
+        match *self {
+            Value::Str(ref s) => Cow::Borrowed(s),
+            _ => Cow::Owned(self.to_string()),
+        }
+    }
+}
+
+/// Parses an ISO 8601 date or UTC date and time, like `2016-05-01` or `2016-05-01T12:30:00Z`,
+/// into seconds since the epoch. Days the month doesn't have, like `2016-02-31`, are refused.
+pub fn parse_time(raw: &str) -> Option<i64> {
+    let raw = raw.trim_right_matches('Z');
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d"]
+        .iter()
+        .filter_map(|format| time::strptime(raw, format).ok())
+        .next()
+        .and_then(|tm| {
+            // Days past the end of the month would roll over into the next one
+            let secs = tm.to_timespec().sec;
+            let read = time::at_utc(time::Timespec::new(secs, 0));
+            if read.tm_mon == tm.tm_mon && read.tm_mday == tm.tm_mday {
+                Some(secs)
+            } else {
+                None
+            }
+        })
+}
+
+/// Seconds since the epoch at the start of `year`, UTC.
+pub fn year(year: i64) -> i64 {
+    let tm = time::Tm {
+        tm_year: (year - 1900) as i32,
+        tm_mday: 1,
+        ..time::empty_tm()
+    };
+    tm.to_timespec().sec
+}
+
+/// Formats a time as its year if it's the start of one and as a date if it's midnight,
+/// otherwise as a UTC date and time.
+pub fn format_time(secs: i64) -> String {
+    let tm = time::at_utc(time::Timespec::new(secs, 0));
+    if tm.tm_hour != 0 || tm.tm_min != 0 || tm.tm_sec != 0 {
+        tm.rfc3339().to_string()
+    } else if tm.tm_yday != 0 {
+        format!("{:04}-{:02}-{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday)
+    } else {
+        (tm.tm_year + 1900).to_string()
+    }
+}
+
+/// The span of time a query's `t` predicates compare against: a whole year like `1970`, a day
+/// like `2016-05-01`, or the second of a date and time or a unix timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Span {
+    pub fn parse(raw: &str) -> Option<Span> {
+        match raw.parse::<i64>() {
+            Ok(n) if n.abs() < 10000 => Some(Span::new(year(n), year(n + 1))),
+            Ok(n) => Some(Span::new(n, n + 1)),
+            Err(_) => {
+                parse_time(raw).map(|start| {
+                    if raw.contains('T') {
+                        Span::new(start, start + 1)
+                    } else {
+                        Span::new(start, start + 24 * 60 * 60)
+                    }
+                })
+            }
+        }
+    }
+
+    pub fn new(start: i64, end: i64) -> Span {
+        Span {
+            start: start,
+            end: end,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_time(self.start))
+    }
+}
+
+/// How the cells of a CSV time column are read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeFormat {
+    /// Years, ISO 8601 dates and date-times or unix timestamps, told apart like `Span::parse`
+    Auto,
+    Year,
+    Iso,
+    Epoch,
+    /// A `strptime` pattern like `%d/%m/%Y`
+    Pattern(String),
+}
+
+impl TimeFormat {
+    pub fn parse(raw: &str) -> Option<TimeFormat> {
+        match raw {
+            "auto" => Some(TimeFormat::Auto),
+            "year" => Some(TimeFormat::Year),
+            "iso" => Some(TimeFormat::Iso),
+            "epoch" => Some(TimeFormat::Epoch),
+            _ if raw.contains('%') => Some(TimeFormat::Pattern(raw.to_owned())),
+            _ => None,
+        }
+    }
+
+    /// Seconds since the epoch of a cell, `None` if it isn't in this format.
+    pub fn read(&self, cell: &str) -> Option<i64> {
+        let cell = cell.trim();
+        match *self {
+            TimeFormat::Auto => Span::parse(cell).map(|span| span.start),
+            TimeFormat::Year => cell.parse().ok().map(year),
+            TimeFormat::Iso => parse_time(cell),
+            TimeFormat::Epoch => cell.parse().ok(),
+            TimeFormat::Pattern(ref pattern) => {
+                time::strptime(cell, pattern).ok().map(|tm| tm.to_timespec().sec)
+            }
+        }
+    }
+}
+
+impl Default for TimeFormat {
+    fn default() -> TimeFormat {
+        TimeFormat::Auto
+    }
+}
+
+fn is_numeric(raw: &str) -> bool {
+    raw.chars().any(|c| c.is_digit(10)) &&
+    raw.chars().all(|c| c.is_digit(10) || c == '.' || c == '-' || c == '+' || c == 'e' || c == 'E')
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(ref s) => write!(f, "{}", s),
+            Value::Timestamp(secs) => write!(f, "{}", format_time(secs)),
+            Value::Ref(e) => write!(f, "#{}", e),
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Str(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Float(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+/// A fact asserted, or retracted when `added` is false, by transaction `tx`.
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable, PartialEq)]
 pub struct Datum {
     pub e: usize,
     pub a: String,
-    pub v: String,
-    pub t: usize,
+    pub v: Value,
+    pub t: i64,
+    pub tx: usize,
+    pub added: bool,
 }
 
 impl Datum {
-    pub fn new<S>(e: usize, a: S, v: S, t: usize) -> Datum
-        where S: Into<String>
+    pub fn new<A, V>(e: usize, a: A, v: V, t: i64) -> Datum
+        where A: Into<String>,
+              V: Into<Value>
     {
         Datum {
             e: e,
             a: a.into(),
             v: v.into(),
             t: t,
+            tx: 0,
+            added: true,
         }
     }
 }
 
 impl fmt::Display for Datum {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({}, {}, {}, {})", self.e, self.a, self.v, self.t)
+        write!(f, "{}", Row::from(self))
     }
 }
 
@@ -42,36 +313,131 @@ pub struct Ref {
     pub e: usize,
     pub a: String,
     pub v: usize,
-    pub t: usize,
+    pub t: i64,
+    pub tx: usize,
+    pub added: bool,
 }
 
 impl Ref {
-    pub fn new(e: usize, a: String, v: usize, t: usize) -> Ref {
+    pub fn new(e: usize, a: String, v: usize, t: i64) -> Ref {
         Ref {
             e: e,
             a: a,
             v: v,
             t: t,
+            tx: 0,
+            added: true,
         }
     }
 }
 
 impl fmt::Display for Ref {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({}, {}, {}, {})", self.e, self.a, self.v, self.t)
+        try!(write!(f, "({}, {}, {}, {})", self.e, self.a, self.v, format_time(self.t)));
+        if !self.added {
+            try!(write!(f, " retracted"));
+        }
+        Ok(())
     }
 }
 
+/// Whether `attribute` is one the database asserts about itself: the metadata of transactions
+/// (`tx/`) and attribute declarations (`db/`).
+pub fn is_system(attribute: &str) -> bool {
+    attribute.starts_with("tx/") || attribute.starts_with("db/")
+}
+
+/// One insert or retraction. Its metadata is also asserted as `tx/` datums about entity `e` so
+/// transactions can be queried like any other entity.
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable, PartialEq)]
-pub struct Db {
+pub struct Transaction {
+    pub id: usize,
+    pub e: usize,
+    pub time: i64,
+    pub source: Option<String>,
+    pub note: Option<String>,
+}
+
+impl Transaction {
+    pub fn new(id: usize, e: usize, time: i64, source: Option<String>, note: Option<String>)
+               -> Transaction {
+        Transaction {
+            id: id,
+            e: e,
+            time: time,
+            source: source,
+            note: note,
+        }
+    }
+
+    pub fn datums(&self) -> Vec<Datum> {
+        let t = self.time;
+        let mut datums = vec![Datum::new(self.e, "tx/id", Value::Int(self.id as i64), t),
+                              Datum::new(self.e, "tx/time", Value::Timestamp(self.time), t)];
+        if let Some(ref source) = self.source {
+            datums.push(Datum::new(self.e, "tx/source", source.clone(), t));
+        }
+        if let Some(ref note) = self.note {
+            datums.push(Datum::new(self.e, "tx/note", note.clone(), t));
+        }
+        datums
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "tx {} at {}", self.id, Value::Timestamp(self.time)));
+        if let Some(ref source) = self.source {
+            try!(write!(f, " from {}", source));
+        }
+        if let Some(ref note) = self.note {
+            try!(write!(f, " ({})", note));
+        }
+        Ok(())
+    }
+}
+
+/// A transaction with its stamped datums and refs, ready to be logged and applied. `offset` is
+/// the database's entity offset once it's applied.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct Commit {
+    pub transaction: Transaction,
     pub datums: Vec<Datum>,
     pub refs: Vec<Ref>,
     pub offset: usize,
 }
 
+/// A point in the transaction log, either a transaction id or a unix timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Moment {
+    Tx(usize),
+    Time(i64),
+}
+
+/// Which facts a query sees: only those currently asserted, every assertion and retraction, the
+/// facts asserted at some earlier point or the current facts asserted after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Basis {
+    Current,
+    History,
+    AsOf(Moment),
+    Since(Moment),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Db {
+    pub datums: Storage,
+    pub refs: Vec<Ref>,
+    pub offset: usize,
+    pub transactions: Vec<Transaction>,
+    pub indexes: Indexes,
+    pub ref_index: RefIndex,
+    pub schema: Schema,
+}
+
 #[derive(Debug)]
 pub struct DbView<'a> {
-    pub datums: Vec<&'a Datum>,
+    pub datums: Vec<Row<'a>>,
 }
 
 #[derive(Debug)]
@@ -80,26 +446,33 @@ pub enum Error {
     Csv(csv::Error),
     Encoding(serialize::EncodingError),
     Decoding(serialize::DecodingError),
-    TimeColumnTypeError(String),
     MissingTimeHeader(String),
+    MissingHeader(String),
+    NonConforming(Vec<Mismatch>),
+    Row(RowError),
+    Undecodable(Encoding),
+    NotADatabase,
+    UnsupportedVersion(u32),
+    ChecksumMismatch(&'static str),
+    MissingSection(&'static str),
+    Schema(schema::Error),
 }
 
 impl Db {
     pub fn new() -> Db {
         Db {
-            datums: vec![],
+            datums: Storage::new(),
             refs: vec![],
             offset: 0,
+            transactions: vec![],
+            indexes: Indexes::new(),
+            ref_index: RefIndex::new(),
+            schema: Schema::new(),
         }
     }
 
     pub fn from_file(filename: &str) -> Result<Db, Error> {
-        let file = try!(File::open(filename));
-        let reader = io::BufReader::new(file);
-        let mut decoder = ZlibDecoder::new(reader);
-        let decoded = try!(serialize::decode_from(&mut decoder, SizeLimit::Infinite));
-
-        Ok(decoded)
+        format::read(path::Path::new(filename))
     }
 
     pub fn write(&self, filename: &str) -> Result<(), Error> {
@@ -108,45 +481,313 @@ impl Db {
             return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, filename)));
         }
 
-        let writer = io::BufWriter::new(File::create(path).unwrap());
-        let mut encoder = ZlibEncoder::new(writer, Compression::Fast);
+        self.save(path)
+    }
 
-        try!(bincode::rustc_serialize::encode_into(self, &mut encoder, SizeLimit::Infinite));
-        Ok(())
+    /// Writes a snapshot to `path`, replacing any existing file, and waits for it to be synced.
+    pub fn save(&self, path: &path::Path) -> Result<(), Error> {
+        format::write(self, path)
+    }
+
+    /// Asserts `datums` and `refs` in a new transaction, `offset` is the number of entity ids
+    /// they allocated. Returns the transaction id.
+    pub fn insert(&mut self, datums: Vec<Datum>, refs: Vec<Ref>, offset: usize)
+                  -> Result<usize, Error> {
+        let commit = try!(self.prepare_insert(datums, refs, offset, None, None));
+        Ok(self.apply(commit))
+    }
+
+    /// Records that `datums` and `refs` no longer hold, their assertions are kept as history.
+    pub fn retract(&mut self, datums: Vec<Datum>, refs: Vec<Ref>, note: Option<String>)
+                   -> usize {
+        let commit = self.prepare_retract(datums, refs, note);
+        self.apply(commit)
+    }
+
+    /// Builds the next transaction asserting `datums` and `refs`, recording where they came from
+    /// and why. Datums are checked against the schema, new values of cardinality one attributes
+    /// retract the old ones in the same transaction. Nothing changes until the commit is applied.
+    pub fn prepare_insert(&self,
+                          datums: Vec<Datum>,
+                          refs: Vec<Ref>,
+                          offset: usize,
+                          source: Option<String>,
+                          note: Option<String>)
+                          -> Result<Commit, Error> {
+        let replaced = try!(self.schema.check(self, &datums));
+        Ok(self.prepare(datums, refs, replaced, offset, true, source, note))
+    }
+
+    /// Like `prepare_insert`, but every attribute asserted about an entity replaces its current
+    /// values, as if it had cardinality one, and so do its refs through the same attribute. Used
+    /// to update entities matched on import.
+    pub fn prepare_upsert(&self,
+                          datums: Vec<Datum>,
+                          refs: Vec<Ref>,
+                          offset: usize,
+                          source: Option<String>,
+                          note: Option<String>)
+                          -> Result<Commit, Error> {
+        let mut replaced = try!(self.schema.check(self, &datums));
+        let mut seen = HashSet::new();
+
+        for datum in &datums {
+            let one = self.schema
+                          .get(&datum.a)
+                          .map_or(false, |a| a.cardinality == Cardinality::One);
+            if one || !seen.insert((datum.e, &datum.a[..])) {
+                continue;
+            }
+            replaced.extend(self.indexes
+                                .about(&self.datums, datum.e, &datum.a)
+                                .into_iter()
+                                .filter(|&p| self.visible(p, Basis::Current))
+                                .map(|p| self.datums.row(p).to_datum())
+                                .filter(|d| d.v != datum.v));
+        }
+
+        let mut kept = HashMap::new();
+        for db_ref in &refs {
+            kept.entry((db_ref.e, db_ref.a.clone()))
+                .or_insert_with(HashSet::new)
+                .insert(db_ref.v);
+        }
+        let mut replaced_refs = vec![];
+        for (&(e, ref a), targets) in &kept {
+            replaced_refs.extend(self.current_refs(e, a)
+                                     .into_iter()
+                                     .filter(|r| !targets.contains(&r.v))
+                                     .cloned());
+        }
+        let refs = refs.into_iter()
+                       .filter(|r| !self.current_refs(r.e, &r.a).iter().any(|c| c.v == r.v))
+                       .collect();
+
+        let mut commit = self.prepare(datums, refs, replaced, offset, true, source, note);
+        let tx = commit.transaction.id;
+        commit.refs.extend(replaced_refs.into_iter().map(|r| {
+            Ref {
+                tx: tx,
+                added: false,
+                ..r
+            }
+        }));
+        Ok(commit)
+    }
+
+    // The refs from entity `e` through `attribute` that hold now
+    fn current_refs(&self, e: usize, attribute: &str) -> Vec<&Ref> {
+        self.ref_index
+            .forward
+            .get(&e)
+            .map_or(vec![], |positions| {
+                positions.iter()
+                         .filter(|&&p| self.ref_visible(p, Basis::Current))
+                         .map(|&p| &self.refs[p])
+                         .filter(|r| r.a == attribute)
+                         .collect()
+            })
+    }
+
+    /// Builds the transaction retracting `datums` and `refs`. Entities left without a current
+    /// datum also lose the current refs from and to them.
+    pub fn prepare_retract(&self, datums: Vec<Datum>, mut refs: Vec<Ref>, note: Option<String>)
+                           -> Commit {
+        let eids = datums.iter().map(|d| d.e).collect::<HashSet<usize>>();
+        let remaining = {
+            let mut retracting: HashMap<usize, Vec<&Datum>> = HashMap::new();
+            for datum in &datums {
+                retracting.entry(datum.e).or_insert_with(Vec::new).push(datum);
+            }
+            let mut remaining = HashSet::new();
+            for p in self.indexes.entities(&self.datums, &eids) {
+                let row = self.datums.row(p);
+                if self.visible(p, Basis::Current) &&
+                   !retracting[&row.e].iter().any(|&d| row.same_fact(&Row::from(d))) {
+                    remaining.insert(row.e);
+                }
+            }
+            remaining
+        };
+
+        let mut orphaned = eids.difference(&remaining)
+                               .flat_map(|e| {
+                                   self.ref_index
+                                       .forward
+                                       .get(e)
+                                       .into_iter()
+                                       .chain(self.ref_index.reverse.get(e))
+                               })
+                               .flat_map(|positions| positions.iter().cloned())
+                               .filter(|&p| self.ref_visible(p, Basis::Current))
+                               .collect::<Vec<usize>>();
+        // A ref between two retracted entities is found from both
+        orphaned.sort();
+        orphaned.dedup();
+        refs.extend(orphaned.into_iter().map(|p| self.refs[p].clone()));
+
+        self.prepare(datums, refs, vec![], 0, false, None, note)
+    }
+
+    /// Builds the transaction declaring `attribute`, the datums already about it must conform.
+    pub fn prepare_declare(&self, attribute: Attribute, note: Option<String>)
+                           -> Result<Commit, Error> {
+        try!(self.schema.check_declaration(self, &attribute));
+        let datums = attribute.datums(self.offset + 1, time::get_time().sec);
+        Ok(self.prepare(datums, vec![], vec![], 1, true, None, note))
+    }
+
+    fn prepare(&self,
+               mut datums: Vec<Datum>,
+               mut refs: Vec<Ref>,
+               retracted: Vec<Datum>,
+               offset: usize,
+               added: bool,
+               source: Option<String>,
+               note: Option<String>)
+               -> Commit {
+        let tx = self.transactions.len();
+        let tx_eid = self.offset + offset + 1;
+        let transaction = Transaction::new(tx, tx_eid, time::get_time().sec, source, note);
+
+        for datum in &mut datums {
+            datum.tx = tx;
+            datum.added = added;
+        }
+        for db_ref in &mut refs {
+            db_ref.tx = tx;
+            db_ref.added = added;
+        }
+        datums.extend(retracted.into_iter().map(|d| {
+            Datum {
+                tx: tx,
+                added: false,
+                ..d
+            }
+        }));
+        datums.extend(transaction.datums().into_iter().map(|d| Datum { tx: tx, ..d }));
+
+        Commit {
+            transaction: transaction,
+            datums: datums,
+            refs: refs,
+            offset: tx_eid,
+        }
+    }
+
+    /// Appends a prepared commit, commits must be applied in the order they were prepared.
+    /// Returns the transaction id.
+    pub fn apply(&mut self, commit: Commit) -> usize {
+        let tx = commit.transaction.id;
+
+        let start = self.datums.len();
+        self.offset = commit.offset;
+        self.datums.extend(commit.datums);
+        self.refs.extend(commit.refs);
+        self.transactions.push(commit.transaction);
+        self.indexes.append(&self.datums, start);
+        self.ref_index = RefIndex::build(&self.refs);
+        self.schema = Schema::build(self);
+        tx
+    }
+
+    /// Fails with the damaged section if reading the mapped file found one.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.datums.damaged() {
+            Some(section) => Err(Error::ChecksumMismatch(section)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn visible(&self, position: usize, basis: Basis) -> bool {
+        let fact = self.datums.fact(position);
+        self.sees(fact.tx, fact.added, self.indexes.retraction(position), basis)
     }
 
-    pub fn insert(&mut self, datums: Vec<Datum>, refs: Vec<Ref>, offset: usize) {
-        self.datums.extend(datums);
-        self.refs.extend(refs);
-        self.offset += offset;
+    pub fn ref_visible(&self, position: usize, basis: Basis) -> bool {
+        let db_ref = &self.refs[position];
+        self.sees(db_ref.tx, db_ref.added, self.ref_index.retracted[position], basis)
+    }
+
+    fn sees(&self, tx: usize, added: bool, retracted: Option<usize>, basis: Basis) -> bool {
+        match basis {
+            Basis::Current => added && retracted.is_none(),
+            Basis::History => true,
+            Basis::AsOf(moment) => {
+                added && self.reached(tx, moment) &&
+                retracted.map_or(true, |r| !self.reached(r, moment))
+            }
+            Basis::Since(moment) => added && !self.reached(tx, moment) && retracted.is_none(),
+        }
+    }
+
+    // Whether transaction `tx` had been committed at `moment`
+    fn reached(&self, tx: usize, moment: Moment) -> bool {
+        match moment {
+            Moment::Tx(id) => tx <= id,
+            Moment::Time(secs) => self.transactions[tx].time <= secs,
+        }
+    }
+
+    /// Entities referenced through `attribute` by one of `eids`.
+    pub fn referenced(&self, eids: &HashSet<usize>, attribute: &str, basis: Basis)
+                      -> HashSet<usize> {
+        let mut referenced = HashSet::new();
+        for eid in eids {
+            if let Some(positions) = self.ref_index.forward.get(eid) {
+                referenced.extend(positions.iter()
+                                           .filter(|&&p| self.ref_visible(p, basis))
+                                           .map(|&p| &self.refs[p])
+                                           .filter(|r| r.a == attribute)
+                                           .map(|r| r.v));
+            }
+        }
+        referenced
+    }
+
+    /// Entities referencing one of `eids` through `attribute`.
+    pub fn referrers(&self, eids: &HashSet<usize>, attribute: &str, basis: Basis)
+                     -> HashSet<usize> {
+        let mut referrers = HashSet::new();
+        for eid in eids {
+            if let Some(positions) = self.ref_index.reverse.get(eid) {
+                referrers.extend(positions.iter()
+                                          .filter(|&&p| self.ref_visible(p, basis))
+                                          .map(|&p| &self.refs[p])
+                                          .filter(|r| r.a == attribute)
+                                          .map(|r| r.e));
+            }
+        }
+        referrers
     }
 }
 
 impl fmt::Display for Db {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f, "datums:\n"));
-        try!(display_datums(&self.datums.iter().collect::<Vec<&Datum>>(), f, 20));
+        try!(display_datums(self.datums.rows(), self.datums.len(), f, 20));
         try!(write!(f, "\nrefs:\n"));
-        display_datums(&self.refs.iter().collect::<Vec<&Ref>>(), f, 20)
+        display_datums(self.refs.iter(), self.refs.len(), f, 20)
     }
 }
 
 impl<'a> fmt::Display for DbView<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        display_datums(&self.datums, f, 20)
+        display_datums(self.datums.iter(), self.datums.len(), f, 20)
     }
 }
 
-fn display_datums<T>(datums: &[&T], f: &mut fmt::Formatter, size: usize) -> fmt::Result
-    where T: fmt::Display
+fn display_datums<I>(datums: I, len: usize, f: &mut fmt::Formatter, size: usize) -> fmt::Result
+    where I: Iterator,
+          I::Item: fmt::Display
 {
-    if datums.len() == 0 {
+    if len == 0 {
         try!(write!(f, "()"))
     }
 
-    for (idx, datum) in datums.iter().enumerate().take(size) {
+    for (idx, datum) in datums.enumerate().take(size) {
         try!(write!(f, "{}", datum));
-        if idx < datums.len() - 1 {
+        if idx < len - 1 {
             try!(write!(f, "\n"));
         }
         if idx == size - 1 {
@@ -174,6 +815,12 @@ impl From<serialize::DecodingError> for Error {
     }
 }
 
+impl From<schema::Error> for Error {
+    fn from(err: schema::Error) -> Error {
+        Error::Schema(err)
+    }
+}
+
 impl From<csv::Error> for Error {
     fn from(err: csv::Error) -> Error {
         Error::Csv(err)
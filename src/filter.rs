@@ -1,182 +1,453 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::mpsc::channel;
 use scoped_threadpool::Pool;
+use time;
 
-use ast::{AstNode, Comparator};
-use data::{Datum, Db, DbView};
+use ast::{AstNode, Comparator, Predicates};
+use data::{is_system, Basis, Db, DbView, Span, Value};
+use index::IndexKind;
+use plan::{IndexedNode, Plan};
+use storage::{Fact, Storage};
+
+#[derive(Debug)]
+pub struct TaskStats {
+    pub id: usize,
+    pub rows: usize,
+    pub scanned: Option<usize>,
+    pub index: Option<IndexKind>,
+}
+
+#[derive(Debug)]
+pub struct StageStats {
+    pub stage: usize,
+    pub tasks: Vec<TaskStats>,
+    pub duration: f64,
+    /// The jobs queued on the pool, scans are split into one job per thread
+    pub jobs: usize,
+}
 
 pub struct Filter<'a> {
     db: &'a Db,
     pool: &'a mut Pool,
+    basis: Basis,
 }
 
 impl<'a> Filter<'a> {
     pub fn new(db: &'a Db, pool: &'a mut Pool) -> Filter<'a> {
+        Filter::with_basis(db, pool, Basis::Current)
+    }
+
+    pub fn with_basis(db: &'a Db, pool: &'a mut Pool, basis: Basis) -> Filter<'a> {
         Filter {
             db: db,
             pool: pool,
+            basis: basis,
         }
     }
 
     pub fn execute(self, ast: &AstNode) -> DbView<'a> {
+        self.analyze(ast).0
+    }
+
+    /// Executes the query while recording the rows, timing and jobs of every stage.
+    pub fn analyze(mut self, ast: &AstNode) -> (DbView<'a>, Vec<StageStats>) {
         let plan = Plan::new(ast);
-        let mut cache = Cache { executions: vec![] };
+        let mut results: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut stats = vec![];
 
-        for (i, step) in plan.steps.iter().enumerate() {
-            if i < plan.steps.len() - 1 {
-                let from_datums = Self::run_step(self.db, self.pool, &cache, step);
-                let from_eids = Self::extract_eids(from_datums);
-                cache.executions.push(self.translate_eids(from_eids));
-            }
+        for (i, stage) in plan.stages().iter().enumerate() {
+            let start = time::precise_time_s();
+            let (stage_results, tasks, jobs) = self.run_stage(&plan, stage, &results);
+
+            stats.push(StageStats {
+                stage: i,
+                tasks: tasks,
+                duration: time::precise_time_s() - start,
+                jobs: jobs,
+            });
+            results.extend(stage_results);
         }
 
-        DbView { datums: Self::run_step(self.db, self.pool, &cache, plan.steps.last().unwrap()) }
+        let db = self.db;
+        let positions = results.remove(&plan.root()).unwrap_or_else(Vec::new);
+        (DbView { datums: positions.into_iter().map(|p| db.datums.row(p)).collect() }, stats)
     }
 
-    fn translate_eids(&self, eids: Vec<usize>) -> Vec<usize> {
-        self.db
-            .refs
-            .iter()
-            .map(|db_ref| {
-                for eid in &eids {
-                    if *eid == db_ref.e {
-                        return Some(db_ref.v);
-                    } else if *eid == db_ref.v {
-                        return Some(db_ref.e);
-                    }
-                }
-                None
-            })
-            .filter(|db_ref| db_ref.is_some())
-            .map(|db_ref| db_ref.unwrap())
-            .collect()
+    // Follow every ref touching one of `eids` to the entity on its other end
+    fn translate_eids(&self, eids: &HashSet<usize>) -> HashSet<usize> {
+        let db = self.db;
+        let visible = |p: &&usize| db.ref_visible(**p, self.basis);
+        let mut translated = HashSet::new();
+
+        for eid in eids {
+            if let Some(positions) = db.ref_index.forward.get(eid) {
+                translated.extend(positions.iter().filter(&visible).map(|&p| db.refs[p].v));
+            }
+            if let Some(positions) = db.ref_index.reverse.get(eid) {
+                translated.extend(positions.iter().filter(&visible).map(|&p| db.refs[p].e));
+            }
+        }
+        translated
     }
 
-    fn run_step(db: &'a Db, pool: &mut Pool, cache: &Cache, ast: &AstNode) -> Vec<&'a Datum> {
-        let db_size = db.datums.len();
-        let threads = pool.thread_count() as usize;
-        let size = db.datums.len() / threads;
+    // Tasks within a stage only depend on earlier stages, so every task in it runs at once.
+    // Scans are split into one job per thread, results are positions into `Db::datums`.
+    fn run_stage(&mut self, plan: &Plan, stage: &[usize], results: &HashMap<usize, Vec<usize>>)
+                 -> (HashMap<usize, Vec<usize>>, Vec<TaskStats>, usize) {
+        let db = self.db;
+        let basis = self.basis;
+        let threads = self.pool.thread_count() as usize;
+        let hidden = hidden(db);
+
+        let scans = stage.iter()
+                         .filter_map(|&id| {
+                             match *plan.task(id) {
+                                 IndexedNode::Base(ref preds) => {
+                                     Some((id, Scan::new(db, preds, None, basis)))
+                                 }
+                                 IndexedNode::Join(ref preds, c) => {
+                                     let eids = Self::extract_eids(db, &results[&c]);
+                                     let joined = self.translate_eids(&eids);
+                                     Some((id, Scan::new(db, preds, Some(joined), basis)))
+                                 }
+                                 IndexedNode::RefJoin(ref preds, ref path, c) => {
+                                     let eids = Self::extract_eids(db, &results[&c]);
+                                     let joined = if path.reverse {
+                                         db.referenced(&eids, &path.attribute, basis)
+                                     } else {
+                                         db.referrers(&eids, &path.attribute, basis)
+                                     };
+                                     Some((id, Scan::new(db, preds, Some(joined), basis)))
+                                 }
+                                 _ => None,
+                             }
+                         })
+                         .collect::<Vec<(usize, Scan)>>();
+
         let (tx, rx) = channel();
+        let jobs = scans.len() * threads + stage.len() - scans.len();
 
-        pool.scoped(|scoped| {
-            for i in 0..threads {
-                let start = i * size;
-                let stop = if i == (threads - 1) { db_size } else { i * size + size };
+        self.pool.scoped(|scoped| {
+            for &(id, ref scan) in &scans {
+                let size = scan.len / threads;
 
+                for i in 0..threads {
+                    let start = i * size;
+                    let stop = if i == (threads - 1) { scan.len } else { i * size + size };
+                    let thread_tx = tx.clone();
+
+                    scoped.execute(move || {
+                        let rows = (start..stop)
+                                       .map(|k| scan.position(k))
+                                       .filter(|&p| {
+                                           db.visible(p, basis) &&
+                                           scan.matches(&db.datums, &db.datums.fact(p))
+                                       })
+                                       .collect::<Vec<usize>>();
+                        thread_tx.send((id, rows)).unwrap();
+                    })
+                }
+            }
+
+            for &id in stage {
                 let thread_tx = tx.clone();
-                let thread_ast = ast.clone();
-                let slice: &'a [Datum] = &db.datums[start..stop];
-
-                scoped.execute(move || {
-                    let results = slice.iter()
-                                       .filter(|d| eval(&thread_ast, cache, d))
-                                       .collect::<Vec<&Datum>>();
-                    thread_tx.send(results).unwrap();
-                })
+
+                match *plan.task(id) {
+                    IndexedNode::Or(l, r) => {
+                        let (left, right) = (&results[&l], &results[&r]);
+                        scoped.execute(move || thread_tx.send((id, union(left, right))).unwrap())
+                    }
+                    IndexedNode::And(l, r) => {
+                        let (left, right) = (&results[&l], &results[&r]);
+                        scoped.execute(move || {
+                            thread_tx.send((id, intersection(left, right))).unwrap()
+                        })
+                    }
+                    IndexedNode::Not(c) => {
+                        let child = &results[&c];
+                        let len = db.datums.len();
+                        let hidden = &hidden;
+                        scoped.execute(move || {
+                            let rows = complement(child, len)
+                                           .into_iter()
+                                           .filter(|&p| {
+                                               db.visible(p, basis) &&
+                                               !hidden.contains(&db.datums.fact(p).a)
+                                           })
+                                           .collect();
+                            thread_tx.send((id, rows)).unwrap()
+                        })
+                    }
+                    _ => (),
+                }
             }
         });
+        drop(tx);
 
-        let mut results: Vec<&Datum> = vec![];
-        for _ in 0..threads {
-            results.extend(rx.recv().unwrap())
+        let mut stage_results = HashMap::new();
+        for (id, rows) in rx.iter() {
+            stage_results.entry(id).or_insert_with(Vec::new).extend(rows);
         }
-        results
+        for rows in stage_results.values_mut() {
+            rows.sort();
+        }
+
+        let tasks = stage.iter()
+                         .map(|&id| {
+                             let scan = scans.iter().find(|s| s.0 == id).map(|s| &s.1);
+                             TaskStats {
+                                 id: id,
+                                 rows: stage_results[&id].len(),
+                                 scanned: scan.map(|s| s.len),
+                                 index: scan.and_then(|s| s.index),
+                             }
+                         })
+                         .collect();
+
+        (stage_results, tasks, jobs)
     }
 
-    fn extract_eids(datums: Vec<&Datum>) -> Vec<usize> {
-        let mut eids: Vec<usize> = datums.into_iter().map(|d| d.e).collect::<Vec<usize>>();
-        eids.sort();
-        eids.dedup();
-        eids
+    fn extract_eids(db: &Db, positions: &[usize]) -> HashSet<usize> {
+        positions.iter().map(|&p| db.datums.fact(p).e).collect()
     }
 }
 
-struct Plan {
-    steps: Vec<AstNode>,
+struct Scan<'a> {
+    preds: &'a Predicates,
+    // Equality on attributes, and on values only equal to themselves, is tested on ids. `None`
+    // is an id that was never stored, so nothing matches.
+    attribute_ids: Vec<Option<u32>>,
+    value_ids: Vec<Option<u32>>,
+    attributes: Vec<(String, Comparator)>,
+    values: Vec<(Value, Comparator)>,
+    index: Option<IndexKind>,
+    positions: Option<Vec<usize>>,
+    eids: Option<HashSet<usize>>,
+    // Attributes left out of the results, see `hidden`
+    hidden: HashSet<u32>,
+    // The entities holding a non-null value of each attribute in a has(...) predicate
+    has: Vec<HashSet<usize>>,
+    len: usize,
 }
 
-impl Plan {
-    fn new(ast: &AstNode) -> Plan {
-        Plan { steps: Self::expand(ast) }
-    }
+impl<'a> Scan<'a> {
+    fn new(db: &Db, preds: &'a Predicates, eids: Option<HashSet<usize>>, basis: Basis)
+           -> Scan<'a> {
+        let null = db.datums.values.get(&Value::Null);
+        let has = preds.has
+                       .iter()
+                       .map(|attribute| {
+                           db.indexes
+                             .attribute(&db.datums, attribute)
+                             .into_iter()
+                             .filter(|&p| db.visible(p, basis))
+                             .map(|p| db.datums.fact(p))
+                             .filter(|fact| Some(fact.v) != null)
+                             .map(|fact| fact.e)
+                             .collect::<HashSet<usize>>()
+                       })
+                       .collect::<Vec<HashSet<usize>>>();
 
-    fn expand(ast: &AstNode) -> Vec<AstNode> {
-        match *ast {
-            AstNode::Join(ref p, ref c) => {
-                let mut expanded = Plan::expand(c);
-                let len = expanded.len();
-                expanded.push(AstNode::CachedJoin(p.clone(), len - 1));
-                expanded
+        // Without a usable predicate a join, or a has(...), still only needs to visit its
+        // entities
+        let (index, positions) = match (db.indexes.scan(&db.datums, preds),
+                                        eids.as_ref().or(has.first())) {
+            (Some((kind, positions)), _) => (Some(kind), Some(positions)),
+            (None, Some(eids)) => {
+                (Some(IndexKind::Eavt), Some(db.indexes.entities(&db.datums, eids)))
             }
-            AstNode::Or(ref l, ref r) => {
-                let mut expanded = Plan::expand(l);
-                expanded.append(&mut Plan::expand(r));
-                expanded
+            (None, None) => (None, None),
+        };
+        let len = positions.as_ref().map_or(db.datums.len(), |p| p.len());
+
+        let (mut attribute_ids, mut attributes) = (vec![], vec![]);
+        for &(ref attribute, ref comp) in &preds.a {
+            match *comp {
+                Comparator::Equal => attribute_ids.push(db.datums.attributes.get(attribute)),
+                _ => attributes.push((attribute.clone(), comp.clone())),
             }
-            _ => vec![ast.clone()],
         }
-    }
-}
 
-struct Cache {
-    executions: Vec<Vec<usize>>,
-}
+        let (mut value_ids, mut values) = (vec![], vec![]);
+        for &(ref value, ref comp) in &preds.v {
+            // Ints and floats of the same number are equal but have different ids
+            let numeric = match *value {
+                Value::Int(_) | Value::Float(_) => true,
+                _ => false,
+            };
+            if *comp == Comparator::Equal && !numeric {
+                value_ids.push(db.datums.values.get(value));
+            } else {
+                values.push((value.clone(), comp.clone()));
+            }
+        }
 
-fn eval(ast: &AstNode, cache: &Cache, datum: &Datum) -> bool {
-    match *ast {
-        AstNode::True => true,
-        AstNode::Expression(ref preds) => {
-            test_predicate(&preds.e, datum.e) && test_predicate_with_contains(&preds.a, &datum.a) &&
-            test_predicate_with_contains(&preds.v, &datum.v) &&
-            test_predicate(&preds.t, datum.t)
+        Scan {
+            preds: preds,
+            attribute_ids: attribute_ids,
+            value_ids: value_ids,
+            attributes: attributes,
+            values: values,
+            index: index,
+            positions: positions,
+            eids: eids,
+            hidden: if preds.e.is_empty() && preds.a.is_empty() {
+                hidden(db)
+            } else {
+                HashSet::new()
+            },
+            has: has,
+            len: len,
         }
+    }
 
-        AstNode::CachedJoin(ref preds, cache_idx) => {
-            test_join_predicate(&preds.e, &cache.executions[cache_idx], datum.e) &&
-            test_predicate_with_contains(&preds.a, &datum.a) &&
-            test_predicate_with_contains(&preds.v, &datum.v) &&
-            test_predicate(&preds.t, datum.t)
+    fn position(&self, k: usize) -> usize {
+        match self.positions {
+            Some(ref p) => p[k],
+            None => k,
         }
-        AstNode::Or(ref l, ref r) => eval(l, cache, datum) || eval(r, cache, datum),
-        AstNode::Join(_, _) => unimplemented!(),
+    }
+
+    // Only reads the datum's attribute and value when a predicate can't be tested on ids
+    fn matches(&self, datums: &Storage, fact: &Fact) -> bool {
+        let joined = match self.eids {
+            Some(ref eids) => eids.contains(&fact.e),
+            None => true,
+        };
+        joined && !self.hidden.contains(&fact.a) &&
+        self.has.iter().all(|eids| eids.contains(&fact.e)) &&
+        test_predicates(&self.preds.e, fact.e) &&
+        test_time_predicates(&self.preds.t, fact.t) &&
+        self.attribute_ids.iter().all(|&id| id == Some(fact.a)) &&
+        self.value_ids.iter().all(|&id| id == Some(fact.v)) &&
+        test_predicates_with_contains(&self.attributes, datums.attributes.name(fact.a)) &&
+        (self.values.is_empty() ||
+         test_value_predicates(&self.values, &datums.values.value(fact.v)))
     }
 }
 
-fn test_predicate(pred: &Option<(usize, Comparator)>, datum_val: usize) -> bool {
-    match *pred {
-        Some((v, ref comp)) => comp.test_int(datum_val, v),
-        None => true,
+impl fmt::Display for StageStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "stage {}: {:.6}s in {} job(s)",
+                    self.stage,
+                    self.duration,
+                    self.jobs));
+
+        for task in &self.tasks {
+            try!(write!(f, "\n  #{}: {} rows", task.id, task.rows));
+            match (task.scanned, task.index) {
+                (Some(n), Some(kind)) => try!(write!(f, ", {} scanned via {}", n, kind)),
+                (Some(n), None) => try!(write!(f, ", {} scanned in full", n)),
+                _ => (),
+            }
+        }
+        Ok(())
     }
 }
 
-fn test_predicate_with_contains(pred: &Option<(String, Comparator)>, datum_val: &str) -> bool {
-    match *pred {
-        Some((ref v, ref comp)) => comp.test_str(datum_val, &v),
-        None => true,
+fn union(left: &[usize], right: &[usize]) -> Vec<usize> {
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+
+    while i < left.len() && j < right.len() {
+        if left[i] < right[j] {
+            merged.push(left[i]);
+            i += 1;
+        } else if left[i] > right[j] {
+            merged.push(right[j]);
+            j += 1;
+        } else {
+            merged.push(left[i]);
+            i += 1;
+            j += 1;
+        }
     }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+    merged
 }
 
-fn test_join_predicate(pred: &Option<(usize, Comparator)>, eids: &[usize], datum_val: usize) -> bool {
-    match *pred {
-        Some(_) => eids.contains(&datum_val),
-        None => true,
+fn intersection(left: &[usize], right: &[usize]) -> Vec<usize> {
+    let mut shared = vec![];
+    let (mut i, mut j) = (0, 0);
+
+    while i < left.len() && j < right.len() {
+        if left[i] < right[j] {
+            i += 1;
+        } else if left[i] > right[j] {
+            j += 1;
+        } else {
+            shared.push(left[i]);
+            i += 1;
+            j += 1;
+        }
     }
+    shared
+}
+
+fn complement(positions: &[usize], len: usize) -> Vec<usize> {
+    let mut excluded = positions.iter().peekable();
+    (0..len)
+        .filter(|p| {
+            while excluded.peek().map_or(false, |&&e| e < *p) {
+                excluded.next();
+            }
+            excluded.peek().map_or(true, |&&e| e != *p)
+        })
+        .collect()
+}
+
+fn test_predicates(preds: &[(usize, Comparator)], datum_val: usize) -> bool {
+    preds.iter().all(|&(v, ref comp)| comp.test_int(datum_val, v))
+}
+
+fn test_time_predicates(preds: &[(Span, Comparator)], time: i64) -> bool {
+    preds.iter().all(|&(ref span, ref comp)| comp.test_time(time, span))
+}
+
+fn test_predicates_with_contains(preds: &[(String, Comparator)], datum_val: &str) -> bool {
+    preds.iter().all(|&(ref v, ref comp)| comp.test_str(datum_val, v))
+}
+
+// The ids of the attributes the database asserts about itself. Scans without an entity or
+// attribute predicate, and negations, leave their datums out so transactions and declarations
+// only show up when they're asked for.
+fn hidden(db: &Db) -> HashSet<u32> {
+    db.datums
+      .attributes
+      .names()
+      .iter()
+      .enumerate()
+      .filter(|&(_, name)| is_system(name))
+      .map(|(id, _)| id as u32)
+      .collect()
+}
+
+fn test_value_predicates(preds: &[(Value, Comparator)], datum_val: &Value) -> bool {
+    preds.iter().all(|&(ref v, ref comp)| comp.test_value(datum_val, v))
 }
 
 #[cfg(test)]
 mod tests {
     use super::Filter;
     use ast::AstNode;
-    use data::{Db, Datum};
+    use data::{parse_time, year, Basis, Db, Datum, Moment, Ref, Value};
+    use schema::ValueType;
     use scoped_threadpool::Pool;
+    use storage::Row;
 
     fn exec(datums: Vec<Datum>, ast: AstNode) -> Vec<Datum> {
-        let mut pool = Pool::new(1);
-        let db = Db {
-            offset: datums.len(),
-            datums: datums,
-            refs: vec![],
-        };
-        Filter::new(&db, &mut pool).execute(&ast).datums.into_iter().cloned().collect()
+        exec_with_refs(datums, vec![], ast)
+    }
+
+    fn exec_with_refs(datums: Vec<Datum>, refs: Vec<Ref>, ast: AstNode) -> Vec<Datum> {
+        let mut pool = Pool::new(2);
+        let mut db = Db::new();
+        let offset = datums.len();
+        db.insert(datums, refs, offset).unwrap();
+        Filter::new(&db, &mut pool).execute(&ast).datums.iter().map(Row::to_datum).collect()
     }
 
     #[test]
@@ -195,16 +466,183 @@ mod tests {
     #[test]
     fn execute_multiple_predicates() {
         let ast = AstNode::parse("e=1 a=foo v=baz t=1").unwrap();
-        let data = vec![Datum::new(1, "foo", "baz", 1), Datum::new(1, "bar", "baz", 2)];
+        let data = vec![Datum::new(1, "foo", "baz", year(1)), Datum::new(1, "bar", "baz", year(2))];
         assert_eq!(data[..1], exec(data.clone(), ast)[..])
     }
 
     #[test]
     fn execute_operators() {
         let ast = AstNode::parse("a:foo t>=2").unwrap();
-        let data = vec![Datum::new(1, "foobar", "baz", 3),
-                        Datum::new(1, "barfoo", "baz", 2),
-                        Datum::new(1, "foobar", "baz", 1)];
+        let data = vec![Datum::new(1, "foobar", "baz", year(3)),
+                        Datum::new(1, "barfoo", "baz", year(2)),
+                        Datum::new(1, "foobar", "baz", year(1))];
         assert_eq!(data[..2], exec(data.clone(), ast)[..])
     }
+
+    #[test]
+    fn execute_numeric_values() {
+        let ast = AstNode::parse("v>100").unwrap();
+        let data = vec![Datum::new(1, "gdp/value", Value::Int(99), 1),
+                        Datum::new(2, "gdp/value", Value::Int(1000), 1),
+                        Datum::new(3, "gdp/value", Value::Float(100.5), 1)];
+        assert_eq!(data[1..], exec(data.clone(), ast)[..])
+    }
+
+    #[test]
+    fn execute_or() {
+        let ast = AstNode::parse("e=1 | e=3").unwrap();
+        let data = vec![Datum::new(1, "foo", "baz", 1),
+                        Datum::new(2, "foo", "baz", 1),
+                        Datum::new(3, "foo", "baz", 1)];
+        assert_eq!(vec![data[0].clone(), data[2].clone()], exec(data.clone(), ast))
+    }
+
+    #[test]
+    fn execute_and_not() {
+        let ast = AstNode::parse("a=track/artist !(v='Pink Floyd')").unwrap();
+        let data = vec![Datum::new(1, "track/artist", "Pink Floyd", 1967),
+                        Datum::new(2, "track/artist", "Led Zeppelin", 1969),
+                        Datum::new(2, "track/name", "Thank You", 1969)];
+        assert_eq!(data[1..2], exec(data.clone(), ast)[..])
+    }
+
+    #[test]
+    fn execute_presence() {
+        let data = vec![Datum::new(1, "gdp/country", "Canada", 1970),
+                        Datum::new(1, "gdp/value", Value::Int(100), 1970),
+                        Datum::new(2, "gdp/country", "Chad", 1970),
+                        Datum::new(2, "gdp/value", Value::Null, 1970),
+                        Datum::new(3, "gdp/country", "Peru", 1970)];
+        let has = AstNode::parse("a=gdp/country has(gdp/value)").unwrap();
+        assert_eq!(data[0..1], exec(data.clone(), has)[..]);
+        let missing = AstNode::parse("a=gdp/country !has(gdp/value)").unwrap();
+        assert_eq!(vec![data[2].clone(), data[4].clone()], exec(data.clone(), missing));
+    }
+
+    #[test]
+    fn execute_ranges() {
+        let ast = AstNode::parse("t>=1970 t<1980").unwrap();
+        let data = vec![Datum::new(1, "album/name", "Led Zeppelin II", year(1969)),
+                        Datum::new(2, "album/name", "Houses of the Holy", year(1973)),
+                        Datum::new(3, "album/name", "Animals", parse_time("1977-01-23").unwrap()),
+                        Datum::new(4, "album/name", "The Wall", year(1980))];
+        assert_eq!(data[1..3], exec(data.clone(), ast)[..])
+    }
+
+    #[test]
+    fn execute_inferred_dates() {
+        let cells = ["1969-10-22", "1973-03-28", "1980-01-01"];
+        let date_type = ValueType::infer(cells.iter().cloned());
+        assert_eq!(ValueType::Timestamp, date_type);
+        let data = cells.iter()
+                        .enumerate()
+                        .map(|(e, cell)| {
+                            Datum::new(e + 1, "album/released", date_type.convert(cell).unwrap(), 1)
+                        })
+                        .collect::<Vec<_>>();
+
+        let query = |q| exec(data.clone(), AstNode::parse(q).unwrap());
+        assert_eq!(data[1..2], query("v=1973-03-28")[..]);
+        assert_eq!(data[2..], query("v>1973-03-28")[..]);
+        assert_eq!(data[..2], query("v<1980-01-01T00:00:00Z")[..]);
+    }
+
+    #[test]
+    fn execute_indexed() {
+        let ast = AstNode::parse("a=gdp/value v>=100 v<1000").unwrap();
+        let data = vec![Datum::new(1, "gdp/value", Value::Int(1000), 1970),
+                        Datum::new(1, "gdp/country_name", "Chile", 1970),
+                        Datum::new(2, "gdp/value", Value::Float(100.5), 1970),
+                        Datum::new(3, "gdp/value", Value::Int(99), 1970),
+                        Datum::new(4, "gdp/value", Value::Int(500), 1970)];
+        assert_eq!(vec![data[2].clone(), data[4].clone()], exec(data.clone(), ast))
+    }
+
+    #[test]
+    fn execute_or_of_joins() {
+        let ast = AstNode::parse("a=artist/name (e:(v=Meddle) | e:(v=IV))").unwrap();
+        let data = vec![Datum::new(1, "artist/name", "Pink Floyd", 1965),
+                        Datum::new(2, "artist/name", "Led Zeppelin", 1968),
+                        Datum::new(3, "artist/name", "Queen", 1970),
+                        Datum::new(4, "album/name", "Meddle", 1971),
+                        Datum::new(5, "album/name", "IV", 1971)];
+        let refs = vec![Ref::new(4, "album/artist".to_owned(), 1, 1971),
+                        Ref::new(5, "album/artist".to_owned(), 2, 1971)];
+        assert_eq!(data[..2], exec_with_refs(data.clone(), refs, ast)[..])
+    }
+
+    #[test]
+    fn execute_ref_joins() {
+        let data = vec![Datum::new(1, "artist/name", "Led Zeppelin", 1968),
+                        Datum::new(2, "album/name", "Led Zeppelin II", 1969),
+                        Datum::new(3, "track/name", "Thank You", 1969),
+                        Datum::new(4, "track/name", "Whole Lotta Love", 1969)];
+        let refs = vec![Ref::new(3, "track/album".to_owned(), 2, 1969),
+                        Ref::new(3, "track/artist".to_owned(), 1, 1969),
+                        Ref::new(4, "track/artist".to_owned(), 1, 1969)];
+
+        let by_album = AstNode::parse("track/album:(v:Zeppelin)").unwrap();
+        assert_eq!(data[2..3], exec_with_refs(data.clone(), refs.clone(), by_album)[..]);
+
+        let by_artist = AstNode::parse("track/artist:(v:Zeppelin)").unwrap();
+        assert_eq!(data[2..], exec_with_refs(data.clone(), refs.clone(), by_artist)[..]);
+
+        let album_of = AstNode::parse("track/_album:(v='Thank You')").unwrap();
+        assert_eq!(data[1..2], exec_with_refs(data.clone(), refs, album_of)[..]);
+    }
+
+    #[test]
+    fn execute_retractions() {
+        let mut pool = Pool::new(2);
+        let mut db = Db::new();
+        let data = vec![Datum::new(1, "artist/name", "Led Zeppelin", 1968),
+                        Datum::new(2, "artist/name", "Queen", 1970),
+                        Datum::new(3, "album/name", "IV", 1971)];
+        let refs = vec![Ref::new(3, "album/artist".to_owned(), 1, 1971)];
+        db.insert(data.clone(), refs.clone(), 3).unwrap();
+        db.retract(vec![data[1].clone()], refs, None);
+
+        let ast = AstNode::parse("a=artist/name | e:(v=IV)").unwrap();
+        let current = Filter::new(&db, &mut pool).execute(&ast).datums;
+        assert_eq!(vec![Row::from(&data[0])], current);
+
+        let history = Filter::with_basis(&db, &mut pool, Basis::History).execute(&ast).datums;
+        assert_eq!(vec![1, 2, 2], history.iter().map(|d| d.e).collect::<Vec<usize>>());
+        assert_eq!(vec![true, true, false],
+                   history.iter().map(|d| d.added).collect::<Vec<bool>>());
+
+        let not_queen = AstNode::parse("!(v=Queen)").unwrap();
+        assert_eq!(vec![Row::from(&data[0]), Row::from(&data[2])],
+                   Filter::new(&db, &mut pool).execute(&not_queen).datums);
+    }
+
+    #[test]
+    fn execute_as_of_since() {
+        let mut pool = Pool::new(2);
+        let mut db = Db::new();
+        let first = vec![Datum::new(1, "artist/name", "Led Zeppelin", 1968)];
+        let second = vec![Datum::new(2, "artist/name", "Queen", 1970)];
+        db.insert(first.clone(), vec![], 1).unwrap();
+        db.insert(second.clone(), vec![], 1).unwrap();
+        db.retract(first.clone(), vec![], None);
+
+        let ast = AstNode::parse("a=artist/name").unwrap();
+        let names = |db: &Db, pool: &mut Pool, basis: Basis| {
+            Filter::with_basis(db, pool, basis)
+                .execute(&ast)
+                .datums
+                .iter()
+                .map(|d| d.v.to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(vec!["Led Zeppelin"], names(&db, &mut pool, Basis::AsOf(Moment::Tx(0))));
+        assert_eq!(vec!["Led Zeppelin", "Queen"],
+                   names(&db, &mut pool, Basis::AsOf(Moment::Tx(1))));
+        assert_eq!(vec!["Queen"], names(&db, &mut pool, Basis::AsOf(Moment::Tx(2))));
+        assert_eq!(vec!["Queen"], names(&db, &mut pool, Basis::Since(Moment::Tx(0))));
+        assert_eq!(Vec::<String>::new(), names(&db, &mut pool, Basis::Since(Moment::Tx(1))));
+        assert_eq!(Vec::<String>::new(),
+                   names(&db, &mut pool, Basis::AsOf(Moment::Time(db.transactions[0].time - 1))));
+    }
 }
@@ -0,0 +1,634 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize as serialize;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use memmap::{Mmap, Protection};
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use data::{year, Datum, Db, Error, Ref, Value};
+use index::{IndexKind, Indexes, RefIndex};
+use intern::{Dictionary, Symbols};
+use schema::Schema;
+use storage;
+use storage::{encode_value, push_u32, push_u64, read_u32, read_u64, Column, Layout, MappedFile,
+              Storage};
+
+// A database file is laid out as, with every number little endian:
+//
+//   magic | version: u32 | section count: u32 | section table | table checksum: u32 | sections
+//
+// Each table entry is a kind: u32, the section's offset in the file: u64, its length: u64 and
+// an adler32 checksum of its bytes: u32. The table checksum covers everything before it.
+// Readers skip kinds they don't know.
+//
+// Datums and indexes are stored as raw columns, one per field, so the file can be mapped and
+// scanned in place. Their checksums are only written, checking them would read the whole file
+// on open, instead every id and offset is checked against its bounds as it's read (see
+// `MappedFile`). Every other section is zlib compressed bincode and checked when it's read.
+//
+// Version 3 files store times as years, like 1970, rather than seconds since the epoch, they
+// are migrated onto the heap. Version 2 files store the datums and indexes compressed too.
+// Version 1 files have no header, they're a zlib stream holding the whole database with
+// untyped string values.
+
+const MAGIC: &'static [u8] = b"ENTITYQ\0";
+pub const VERSION: u32 = 4;
+const ENTRY_LEN: usize = 4 + 8 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Section {
+    Offset,
+    Datums,
+    Refs,
+    Transactions,
+    Indexes,
+    RefIndex,
+    Attributes,
+    Entities,
+    AttributeIds,
+    ValueIds,
+    Times,
+    Txs,
+    Added,
+    ValueOffsets,
+    Values,
+    Eavt,
+    Aevt,
+    Avet,
+    Retracted,
+}
+
+impl Section {
+    fn from_kind(kind: u32) -> Option<Section> {
+        match kind {
+            1 => Some(Section::Offset),
+            2 => Some(Section::Datums),
+            3 => Some(Section::Refs),
+            4 => Some(Section::Transactions),
+            5 => Some(Section::Indexes),
+            6 => Some(Section::RefIndex),
+            7 => Some(Section::Attributes),
+            8 => Some(Section::Entities),
+            9 => Some(Section::AttributeIds),
+            10 => Some(Section::ValueIds),
+            11 => Some(Section::Times),
+            12 => Some(Section::Txs),
+            13 => Some(Section::Added),
+            14 => Some(Section::ValueOffsets),
+            15 => Some(Section::Values),
+            16 => Some(Section::Eavt),
+            17 => Some(Section::Aevt),
+            18 => Some(Section::Avet),
+            19 => Some(Section::Retracted),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        match *self {
+            Section::Offset => 1,
+            Section::Datums => 2,
+            Section::Refs => 3,
+            Section::Transactions => 4,
+            Section::Indexes => 5,
+            Section::RefIndex => 6,
+            Section::Attributes => 7,
+            Section::Entities => 8,
+            Section::AttributeIds => 9,
+            Section::ValueIds => 10,
+            Section::Times => 11,
+            Section::Txs => 12,
+            Section::Added => 13,
+            Section::ValueOffsets => 14,
+            Section::Values => 15,
+            Section::Eavt => 16,
+            Section::Aevt => 17,
+            Section::Avet => 18,
+            Section::Retracted => 19,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Section::Offset => "offset",
+            Section::Datums => "datums",
+            Section::Refs => "refs",
+            Section::Transactions => "transactions",
+            Section::Indexes => "indexes",
+            Section::RefIndex => "ref index",
+            Section::Attributes => "attributes",
+            Section::Entities => "entities",
+            Section::AttributeIds => "attribute ids",
+            Section::ValueIds => "value ids",
+            Section::Times => "times",
+            Section::Txs => "transaction ids",
+            Section::Added => "added",
+            Section::ValueOffsets => "value offsets",
+            Section::Values => "values",
+            Section::Eavt => "eavt",
+            Section::Aevt => "aevt",
+            Section::Avet => "avet",
+            Section::Retracted => "retracted",
+        }
+    }
+
+    // Bytes a row takes in a datum or index column, `None` for every other section
+    fn width(&self) -> Option<usize> {
+        match *self {
+            Section::Entities | Section::Times | Section::Txs | Section::Eavt | Section::Aevt |
+            Section::Avet | Section::Retracted => Some(8),
+            Section::AttributeIds | Section::ValueIds => Some(4),
+            Section::Added => Some(1),
+            _ => None,
+        }
+    }
+
+    fn is_column(&self) -> bool {
+        self.width().is_some() || *self == Section::ValueOffsets || *self == Section::Values
+    }
+}
+
+/// Writes `db` to `path` in the current format and waits for it to be synced.
+pub fn write(db: &Db, path: &Path) -> Result<(), Error> {
+    let mut sections = datum_columns(&db.datums);
+    for &(section, kind) in &[(Section::Eavt, IndexKind::Eavt),
+                              (Section::Aevt, IndexKind::Aevt),
+                              (Section::Avet, IndexKind::Avet)] {
+        sections.push((section, column_bytes(&db.indexes.sorted(&db.datums, kind))));
+    }
+    let retracted = db.indexes.retraction_column(db.datums.len());
+    sections.push((Section::Retracted, column_bytes(&retracted)));
+    sections.push((Section::Offset, try!(compress(&db.offset))));
+    sections.push((Section::Attributes, try!(compress(&db.datums.attributes.names().to_vec()))));
+    sections.push((Section::Refs, try!(compress(&db.refs))));
+    sections.push((Section::Transactions, try!(compress(&db.transactions))));
+    sections.push((Section::RefIndex, try!(compress(&db.ref_index))));
+
+    let mut header = MAGIC.to_vec();
+    push_u32(&mut header, VERSION);
+    push_u32(&mut header, sections.len() as u32);
+
+    let mut offset = header.len() + sections.len() * ENTRY_LEN + 4;
+    for &(section, ref bytes) in &sections {
+        push_u32(&mut header, section.kind());
+        push_u64(&mut header, offset as u64);
+        push_u64(&mut header, bytes.len() as u64);
+        push_u32(&mut header, adler32(bytes));
+        offset += bytes.len();
+    }
+    let checksum = adler32(&header);
+    push_u32(&mut header, checksum);
+
+    // Don't write out what was read from a damaged file
+    try!(db.check());
+    let mut file = try!(File::create(path));
+    try!(file.write_all(&header));
+    for &(_, ref bytes) in &sections {
+        try!(file.write_all(bytes));
+    }
+    try!(file.sync_all());
+    Ok(())
+}
+
+/// Reads a database written in any supported version, older versions are migrated. Current
+/// files are mapped rather than read, their datums and indexes stay on disk until scanned.
+pub fn read(path: &Path) -> Result<Db, Error> {
+    // Empty files can't be mapped
+    if try!(fs::metadata(path)).len() == 0 {
+        return Err(Error::NotADatabase);
+    }
+    let map = Arc::new(MappedFile::new(try!(Mmap::open_path(path, Protection::Read))));
+    let bytes = storage::bytes(&map);
+
+    if !bytes.starts_with(MAGIC) {
+        return read_legacy(bytes, &path.display().to_string());
+    }
+    if bytes.len() < MAGIC.len() + 8 {
+        return Err(Error::NotADatabase);
+    }
+
+    let version = read_u32(bytes, MAGIC.len());
+    if version < 2 || version > VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let count = read_u32(bytes, MAGIC.len() + 4) as usize;
+    let table_end = MAGIC.len() + 8 + count * ENTRY_LEN;
+    if bytes.len() < table_end + 4 ||
+       adler32(&bytes[..table_end]) != read_u32(bytes, table_end) {
+        return Err(Error::ChecksumMismatch("section table"));
+    }
+
+    let mut db = Db::new();
+    let mut datums = None;
+    let mut attributes = vec![];
+    let mut columns = HashMap::new();
+    let mut ref_indexed = false;
+
+    for i in 0..count {
+        let entry = MAGIC.len() + 8 + i * ENTRY_LEN;
+        let section = match Section::from_kind(read_u32(bytes, entry)) {
+            Some(section) => section,
+            None => continue,
+        };
+
+        let start = read_u64(bytes, entry + 4) as usize;
+        let end = start.saturating_add(read_u64(bytes, entry + 12) as usize);
+        if end > bytes.len() {
+            return Err(Error::ChecksumMismatch(section.name()));
+        }
+        if section.is_column() {
+            columns.insert(section, (start, end));
+            continue;
+        }
+        if adler32(&bytes[start..end]) != read_u32(bytes, entry + 20) {
+            return Err(Error::ChecksumMismatch(section.name()));
+        }
+
+        let stored = &bytes[start..end];
+        match section {
+            Section::Offset => db.offset = try!(decompress(stored)),
+            Section::Datums => datums = Some(try!(decompress::<Vec<Datum>>(stored))),
+            Section::Refs => db.refs = try!(decompress(stored)),
+            Section::Transactions => db.transactions = try!(decompress(stored)),
+            Section::Attributes => attributes = try!(decompress(stored)),
+            Section::RefIndex => {
+                db.ref_index = try!(decompress(stored));
+                ref_indexed = true;
+            }
+            // Version 2 indexes are rebuilt into columns below
+            _ => (),
+        }
+    }
+
+    db.datums = match datums {
+        Some(datums) => Storage::from(datums),
+        None => {
+            let entities = try!(find(&columns, Section::Entities));
+            let len = (entities.1 - entities.0) / 8;
+            let layout = Layout {
+                e: entities.0,
+                a: try!(find_column(&columns, Section::AttributeIds, len)),
+                v: try!(find_column(&columns, Section::ValueIds, len)),
+                t: try!(find_column(&columns, Section::Times, len)),
+                tx: try!(find_column(&columns, Section::Txs, len)),
+                added: try!(find_column(&columns, Section::Added, len)),
+            };
+            let offsets = try!(find(&columns, Section::ValueOffsets));
+            let values = try!(find(&columns, Section::Values));
+            let values = Dictionary::mapped(map.clone(),
+                                            offsets.0,
+                                            values.0,
+                                            values.1,
+                                            ((offsets.1 - offsets.0) / 8).saturating_sub(1));
+            // Damaged ids are read as 0, so there has to be an attribute and a value 0
+            if len > 0 && (attributes.is_empty() || values.is_empty()) {
+                return Err(Error::ChecksumMismatch("value offsets"));
+            }
+            Storage::mapped(map.clone(),
+                            len,
+                            layout,
+                            Symbols::from(attributes),
+                            values,
+                            db.transactions.len())
+        }
+    };
+    if version < VERSION {
+        let datums = db.datums
+                       .rows()
+                       .map(|row| Datum { t: migrate_time(row.t), ..row.to_datum() })
+                       .collect::<Vec<Datum>>();
+        db.datums = Storage::from(datums);
+        for db_ref in &mut db.refs {
+            db_ref.t = migrate_time(db_ref.t);
+        }
+    }
+
+    let len = db.datums.len();
+    db.indexes = match (find_column(&columns, Section::Eavt, len),
+                        find_column(&columns, Section::Aevt, len),
+                        find_column(&columns, Section::Avet, len),
+                        find_column(&columns, Section::Retracted, len)) {
+        (Ok(eavt), Ok(aevt), Ok(avet), Ok(retracted)) if version == VERSION => {
+            let transactions = db.transactions.len() + 1;
+            Indexes::from_columns(Column::Mapped(map.clone(), eavt, len, len),
+                                  Column::Mapped(map.clone(), aevt, len, len),
+                                  Column::Mapped(map.clone(), avet, len, len),
+                                  Column::Mapped(map.clone(), retracted, len, transactions))
+        }
+        _ => Indexes::build(&db.datums),
+    };
+    if !ref_indexed {
+        db.ref_index = RefIndex::build(&db.refs);
+    }
+    // Declarations are datums, the schema is read back from them
+    db.schema = Schema::build(&db);
+    try!(db.check());
+    Ok(db)
+}
+
+// Splits the datums into one column per field. Attribute ids are kept, values are renumbered
+// in the order of their encoding so the mapped dictionary can be searched.
+fn datum_columns(datums: &Storage) -> Vec<(Section, Vec<u8>)> {
+    let mut encoded = (0..datums.values.len() as u32)
+                          .map(|id| {
+                              let mut bytes = vec![];
+                              encode_value(&datums.values.value(id), &mut bytes);
+                              (bytes, id)
+                          })
+                          .collect::<Vec<(Vec<u8>, u32)>>();
+    encoded.sort();
+
+    let mut renumbered = vec![0; encoded.len()];
+    let (mut offsets, mut values) = (vec![], vec![]);
+    push_u64(&mut offsets, 0);
+    for (new_id, (bytes, id)) in encoded.into_iter().enumerate() {
+        renumbered[id as usize] = new_id as u32;
+        values.extend(bytes);
+        push_u64(&mut offsets, values.len() as u64);
+    }
+
+    let (mut e, mut a, mut v) = (vec![], vec![], vec![]);
+    let (mut t, mut tx, mut added) = (vec![], vec![], vec![]);
+    for position in 0..datums.len() {
+        let fact = datums.fact(position);
+        push_u64(&mut e, fact.e as u64);
+        push_u32(&mut a, fact.a);
+        push_u32(&mut v, renumbered[fact.v as usize]);
+        push_u64(&mut t, fact.t as u64);
+        push_u64(&mut tx, fact.tx as u64);
+        added.push(fact.added as u8);
+    }
+
+    vec![(Section::Entities, e),
+         (Section::AttributeIds, a),
+         (Section::ValueIds, v),
+         (Section::Times, t),
+         (Section::Txs, tx),
+         (Section::Added, added),
+         (Section::ValueOffsets, offsets),
+         (Section::Values, values)]
+}
+
+fn column_bytes(column: &[usize]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 * column.len());
+    for &position in column {
+        push_u64(&mut bytes, position as u64);
+    }
+    bytes
+}
+
+fn find(columns: &HashMap<Section, (usize, usize)>, section: Section)
+        -> Result<(usize, usize), Error> {
+    columns.get(&section).cloned().ok_or(Error::MissingSection(section.name()))
+}
+
+// Where a datum or index column starts, checking it holds exactly `len` rows
+fn find_column(columns: &HashMap<Section, (usize, usize)>, section: Section, len: usize)
+               -> Result<usize, Error> {
+    let (start, end) = try!(find(columns, section));
+    if end - start != len * section.width().unwrap_or(0) {
+        return Err(Error::ChecksumMismatch(section.name()));
+    }
+    Ok(start)
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct LegacyDatum {
+    e: usize,
+    a: String,
+    v: String,
+    t: usize,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct LegacyRef {
+    e: usize,
+    a: String,
+    v: usize,
+    t: usize,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct LegacyDb {
+    datums: Vec<LegacyDatum>,
+    refs: Vec<LegacyRef>,
+    offset: usize,
+}
+
+// Version 1 databases become a single transaction asserting every datum and ref
+fn read_legacy(bytes: &[u8], source: &str) -> Result<Db, Error> {
+    let legacy: LegacyDb = match decompress(bytes) {
+        Ok(legacy) => legacy,
+        Err(_) => return Err(Error::NotADatabase),
+    };
+
+    let datums = legacy.datums
+                       .into_iter()
+                       .map(|d| Datum::new(d.e, d.a, Value::parse(&d.v), migrate_time(d.t as i64)))
+                       .collect();
+    let refs = legacy.refs
+                     .into_iter()
+                     .map(|r| Ref::new(r.e, r.a, r.v, migrate_time(r.t as i64)))
+                     .collect();
+
+    let mut db = Db::new();
+    let commit = try!(db.prepare_insert(datums,
+                                        refs,
+                                        legacy.offset,
+                                        Some(source.to_owned()),
+                                        Some("migrated from format version 1".to_owned())));
+    db.apply(commit);
+    Ok(db)
+}
+
+// Times used to be years, except those of transactions which were already seconds
+fn migrate_time(t: i64) -> i64 {
+    if t.abs() < 10000 {
+        year(t)
+    } else {
+        t
+    }
+}
+
+fn compress<T: Encodable>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = ZlibEncoder::new(vec![], Compression::Fast);
+    try!(serialize::encode_into(value, &mut encoder, SizeLimit::Infinite));
+    Ok(try!(encoder.finish()))
+}
+
+fn decompress<T: Decodable>(bytes: &[u8]) -> Result<T, Error> {
+    let mut decoder = ZlibDecoder::new(bytes);
+    Ok(try!(serialize::decode_from(&mut decoder, SizeLimit::Infinite)))
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::SizeLimit;
+    use bincode::rustc_serialize as serialize;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    use super::{adler32, read, write, LegacyDatum, LegacyDb, LegacyRef, Section, ENTRY_LEN,
+                MAGIC};
+    use data::{year, Datum, Db, Error, Ref, Value};
+    use storage::{read_u32, read_u64};
+    use temp_file::TempFile;
+
+    fn music() -> Db {
+        let mut db = Db::new();
+        db.insert(vec![Datum::new(1, "artist/name", "Led Zeppelin", 1968),
+                       Datum::new(2, "album/name", "IV", 1971)],
+                  vec![Ref::new(2, "album/artist".to_owned(), 1, 1971)],
+                  2)
+          .unwrap();
+        db
+    }
+
+    fn rewrite<F: Fn(&mut Vec<u8>)>(path: &Path, change: F) {
+        let mut bytes = vec![];
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        change(&mut bytes);
+        File::create(path).unwrap().write_all(&bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = TempFile::new("round-trip", "db");
+        let path = file.path();
+        let db = music();
+        write(&db, path).unwrap();
+        assert_eq!(db, read(path).unwrap());
+    }
+
+    #[test]
+    fn append_to_mapped() {
+        let (file, copy) = (TempFile::new("mapped", "db"), TempFile::new("mapped-copy", "db"));
+        write(&music(), file.path()).unwrap();
+
+        let mut db = read(file.path()).unwrap();
+        db.insert(vec![Datum::new(4, "artist/name", "Queen", 1970),
+                       Datum::new(4, "album/name", "IV", 1970)],
+                  vec![],
+                  1)
+          .unwrap();
+        assert_eq!(Value::from("Led Zeppelin"), *db.datums.row(0).v);
+        assert_eq!(Value::from("Queen"), *db.datums.row(4).v);
+        assert_eq!(db.datums.fact(1).v, db.datums.fact(5).v);
+        assert_eq!(vec![0, 4, 5],
+                   db.indexes.entities(&db.datums, &vec![1, 4].into_iter().collect()));
+
+        write(&db, copy.path()).unwrap();
+        assert_eq!(db, read(copy.path()).unwrap());
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(0x11e60398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn reject_damaged_files() {
+        let file = TempFile::new("damaged", "db");
+        let path = file.path();
+        write(&music(), path).unwrap();
+        rewrite(path, |bytes| {
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+        });
+        match read(path) {
+            Err(Error::ChecksumMismatch("ref index")) => (),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+
+        write(&music(), path).unwrap();
+        rewrite(path, |bytes| bytes[MAGIC.len()] = 9);
+        match read(path) {
+            Err(Error::UnsupportedVersion(9)) => (),
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+
+        File::create(path).unwrap().write_all(b"year,gdp\n1970,87896095224\n").unwrap();
+        match read(path) {
+            Err(Error::NotADatabase) => (),
+            other => panic!("expected not a database, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_damaged_columns() {
+        let file = TempFile::new("damaged-column", "db");
+        let path = file.path();
+        write(&music(), path).unwrap();
+        rewrite(path, |bytes| {
+            let count = read_u32(bytes, MAGIC.len() + 4) as usize;
+            let entry = (0..count)
+                            .map(|i| MAGIC.len() + 8 + i * ENTRY_LEN)
+                            .find(|&entry| read_u32(bytes, entry) == Section::ValueIds.kind())
+                            .unwrap();
+            let start = read_u64(bytes, entry + 4) as usize;
+            bytes[start + 3] = 0xff;
+        });
+
+        // The first datum's value id is out of bounds, it's found once it's read
+        let damaged = read(path).and_then(|db| {
+            let _ = db.datums.row(0);
+            db.check()
+        });
+        match damaged {
+            Err(Error::ChecksumMismatch("value ids")) => (),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn migrate_version_1() {
+        let file = TempFile::new("legacy", "db");
+        let path = file.path();
+        let legacy = LegacyDb {
+            datums: vec![LegacyDatum {
+                             e: 1,
+                             a: "gdp/value".to_owned(),
+                             v: "87896095224".to_owned(),
+                             t: 1970,
+                         }],
+            refs: vec![LegacyRef {
+                           e: 1,
+                           a: "gdp/country".to_owned(),
+                           v: 2,
+                           t: 1970,
+                       }],
+            offset: 2,
+        };
+        let mut encoder = ZlibEncoder::new(File::create(path).unwrap(), Compression::Fast);
+        serialize::encode_into(&legacy, &mut encoder, SizeLimit::Infinite).unwrap();
+        encoder.finish().unwrap();
+
+        let db = read(path).unwrap();
+        assert_eq!(Value::Int(87896095224), *db.datums.row(0).v);
+        assert_eq!(year(1970), db.datums.row(0).t);
+        assert_eq!(2, db.refs[0].v);
+        assert_eq!(3, db.offset);
+        assert_eq!(Some("migrated from format version 1".to_owned()),
+                   db.transactions[0].note);
+    }
+}
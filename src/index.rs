@@ -0,0 +1,620 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use ast::{Comparator, Predicates};
+use data::{Ref, Value};
+use storage::{Column, Row, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKind {
+    Eavt,
+    Aevt,
+    Avet,
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            IndexKind::Eavt => "EAVT",
+            IndexKind::Aevt => "AEVT",
+            IndexKind::Avet => "AVET",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Positions into `Db::datums` sorted in one order. The base is built, or mapped from a
+/// database file, the datums appended since are merged into a sorted tail of their own so the
+/// base is never read whole.
+#[derive(Debug, Clone)]
+struct Index {
+    base: Column,
+    tail: Column,
+}
+
+impl Index {
+    fn new(base: Column) -> Index {
+        Index {
+            base: base,
+            tail: Column::Heap(vec![]),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.base.len() + self.tail.len()
+    }
+
+    // The positions `probe` finds inside the wanted values, in no particular order
+    fn find<F>(&self, probe: F) -> Vec<usize>
+        where F: Fn(usize) -> Ordering
+    {
+        let mut positions = matching(&self.base, &probe);
+        positions.extend(matching(&self.tail, &probe));
+        positions
+    }
+
+    // `positions` follow every position indexed so far
+    fn append(&mut self, datums: &Storage, positions: &[usize], cmp: fn(&Row, &Row) -> Ordering) {
+        let mut appended = positions.to_vec();
+        appended.sort_by(|&l, &r| cmp(&datums.row(l), &datums.row(r)));
+        let tail = self.tail.slice(0, self.tail.len());
+        self.tail = Column::Heap(merge(datums, &tail, &appended, cmp));
+    }
+
+    fn sorted(&self, datums: &Storage, cmp: fn(&Row, &Row) -> Ordering) -> Vec<usize> {
+        let base = self.base.slice(0, self.base.len());
+        merge(datums, &base, &self.tail.slice(0, self.tail.len()), cmp)
+    }
+}
+
+/// The EAVT, AEVT and AVET orders of the datums, and the transaction that retracted each
+/// assertion. `retracted` holds one more than that transaction or 0 while the assertion holds,
+/// so every column can be mapped straight from a database file. Retractions of datums since
+/// then are kept by position.
+#[derive(Debug, Clone)]
+pub struct Indexes {
+    eavt: Index,
+    aevt: Index,
+    avet: Index,
+    retracted: Column,
+    retractions: HashMap<usize, usize>,
+}
+
+impl Indexes {
+    pub fn new() -> Indexes {
+        Indexes::from_columns(Column::Heap(vec![]),
+                              Column::Heap(vec![]),
+                              Column::Heap(vec![]),
+                              Column::Heap(vec![]))
+    }
+
+    pub fn from_columns(eavt: Column, aevt: Column, avet: Column, retracted: Column) -> Indexes {
+        Indexes {
+            eavt: Index::new(eavt),
+            aevt: Index::new(aevt),
+            avet: Index::new(avet),
+            retracted: retracted,
+            retractions: HashMap::new(),
+        }
+    }
+
+    pub fn build(datums: &Storage) -> Indexes {
+        let rows = datums.rows().collect::<Vec<Row>>();
+        let positions = (0..rows.len()).collect::<Vec<usize>>();
+
+        let mut eavt = positions.clone();
+        eavt.sort_by(|&l, &r| cmp_eavt(&rows[l], &rows[r]));
+
+        let mut aevt = positions.clone();
+        aevt.sort_by(|&l, &r| cmp_aevt(&rows[l], &rows[r]));
+
+        let mut avet = positions;
+        avet.sort_by(|&l, &r| cmp_avet(&rows[l], &rows[r]));
+
+        // Every datum about the same fact is next to each other in EAVT, among the datums
+        // with values that sort the same, like `1` and `1.0`
+        let mut retracted = vec![None; rows.len()];
+        let mut start = 0;
+        while start < eavt.len() {
+            let first = &rows[eavt[start]];
+            let mut end = start + 1;
+            while end < eavt.len() && sorts_with(first, &rows[eavt[end]]) {
+                end += 1;
+            }
+
+            let mut run = eavt[start..end].to_vec();
+            run.sort();
+            while !run.is_empty() {
+                let (fact, rest): (Vec<usize>, Vec<usize>) =
+                    run.iter().partition(|&&p| rows[run[0]].same_fact(&rows[p]));
+                let history = fact.into_iter().map(|p| (p, rows[p].added, rows[p].tx));
+                mark_retractions(history, &mut retracted);
+                run = rest;
+            }
+            start = end;
+        }
+
+        Indexes::from_columns(Column::Heap(eavt),
+                              Column::Heap(aevt),
+                              Column::Heap(avet),
+                              Column::Heap(retracted.into_iter()
+                                                    .map(|r| r.map_or(0, |tx| tx + 1))
+                                                    .collect()))
+    }
+
+    /// Indexes the datums from position `start` on, which were appended after every datum
+    /// indexed so far. Only those datums, and the ones they retract, are read.
+    pub fn append(&mut self, datums: &Storage, start: usize) {
+        let positions = (start..datums.len()).collect::<Vec<usize>>();
+        self.eavt.append(datums, &positions, cmp_eavt);
+        self.aevt.append(datums, &positions, cmp_aevt);
+        self.avet.append(datums, &positions, cmp_avet);
+
+        // A retraction closes every open assertion of the same fact before it
+        for &p in &positions {
+            let retraction = datums.row(p);
+            if retraction.added {
+                continue;
+            }
+            for q in self.about(datums, retraction.e, retraction.a) {
+                let assertion = datums.row(q);
+                if q < p && assertion.added && self.retraction(q).is_none() &&
+                   assertion.same_fact(&retraction) {
+                    self.retractions.insert(q, retraction.tx);
+                }
+            }
+        }
+    }
+
+    /// The transaction that retracted the assertion at `position`, if any.
+    pub fn retraction(&self, position: usize) -> Option<usize> {
+        if let Some(&tx) = self.retractions.get(&position) {
+            return Some(tx);
+        }
+        if position >= self.retracted.len() {
+            return None;
+        }
+        match self.retracted.get(position) {
+            0 => None,
+            tx => Some(tx - 1),
+        }
+    }
+
+    /// Every position in the order of `kind`, to be written to a file.
+    pub fn sorted(&self, datums: &Storage, kind: IndexKind) -> Vec<usize> {
+        match kind {
+            IndexKind::Eavt => self.eavt.sorted(datums, cmp_eavt),
+            IndexKind::Aevt => self.aevt.sorted(datums, cmp_aevt),
+            IndexKind::Avet => self.avet.sorted(datums, cmp_avet),
+        }
+    }
+
+    /// One more than the transaction that retracted each of the `len` positions, or 0.
+    pub fn retraction_column(&self, len: usize) -> Vec<usize> {
+        (0..len).map(|p| self.retraction(p).map_or(0, |tx| tx + 1)).collect()
+    }
+
+    /// Picks the narrowest index range covering every datum that can match `preds` and returns
+    /// its positions in insertion order. `None` means the predicates need a full scan.
+    pub fn scan(&self, datums: &Storage, preds: &Predicates) -> Option<(IndexKind, Vec<usize>)> {
+        let e = rangeable(&preds.e);
+        let a = rangeable(&preds.a);
+        let v = rangeable(&preds.v);
+        let a_is_fixed = a.iter().any(|&&(_, ref comp)| *comp == Comparator::Equal);
+
+        let (kind, mut positions) = if !e.is_empty() {
+            (IndexKind::Eavt, self.eavt.find(|p| probe(&e, |b| datums.fact(p).e.cmp(b))))
+        } else if a_is_fixed && !v.is_empty() {
+            (IndexKind::Avet,
+             self.avet.find(|p| {
+                let d = datums.row(p);
+                match probe(&a, |b| d.a.cmp(b)) {
+                    Ordering::Equal => probe(&v, |b| d.v.total_cmp(b)),
+                    ord => ord,
+                }
+            }))
+        } else if !a.is_empty() {
+            (IndexKind::Aevt, self.aevt.find(|p| probe(&a, |b| datums.attribute(p).cmp(b))))
+        } else {
+            return None;
+        };
+
+        positions.sort();
+        Some((kind, positions))
+    }
+
+    /// Positions of every datum about one of `eids`, in insertion order.
+    pub fn entities(&self, datums: &Storage, eids: &HashSet<usize>) -> Vec<usize> {
+        let mut positions = vec![];
+        for &eid in eids {
+            positions.extend(self.eavt.find(|p| datums.fact(p).e.cmp(&eid)));
+        }
+        positions.sort();
+        positions
+    }
+
+    /// Positions of every datum about `attribute`, in insertion order.
+    pub fn attribute(&self, datums: &Storage, attribute: &str) -> Vec<usize> {
+        let mut positions = self.aevt.find(|p| datums.attribute(p).cmp(attribute));
+        positions.sort();
+        positions
+    }
+
+    /// Positions of the datums about `attribute` of entity `eid`, in insertion order.
+    pub fn about(&self, datums: &Storage, eid: usize, attribute: &str) -> Vec<usize> {
+        let mut positions = self.eavt.find(|p| {
+            match datums.fact(p).e.cmp(&eid) {
+                Ordering::Equal => datums.attribute(p).cmp(attribute),
+                ord => ord,
+            }
+        });
+        positions.sort();
+        positions
+    }
+
+    /// Positions of the datums about `attribute` holding `value`, in insertion order.
+    pub fn holding(&self, datums: &Storage, attribute: &str, value: &Value) -> Vec<usize> {
+        let mut positions = self.avet.find(|p| {
+            let d = datums.row(p);
+            match d.a.cmp(attribute) {
+                Ordering::Equal => d.v.total_cmp(value),
+                ord => ord,
+            }
+        });
+        positions.sort();
+        positions
+    }
+}
+
+// The order of every index follows from the datums, indexes of the same datums only differ in
+// how their positions are split between the base and the tail
+impl PartialEq for Indexes {
+    fn eq(&self, other: &Indexes) -> bool {
+        let len = self.eavt.len();
+        len == other.eavt.len() && self.aevt.len() == other.aevt.len() &&
+        self.avet.len() == other.avet.len() &&
+        (0..len).all(|p| self.retraction(p) == other.retraction(p))
+    }
+}
+
+/// Positions into `Db::refs` keyed by the referring entity (`forward`) and by the referenced
+/// entity (`reverse`).
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable, PartialEq)]
+pub struct RefIndex {
+    pub forward: HashMap<usize, Vec<usize>>,
+    pub reverse: HashMap<usize, Vec<usize>>,
+    pub retracted: Vec<Option<usize>>,
+}
+
+impl RefIndex {
+    pub fn new() -> RefIndex {
+        RefIndex {
+            forward: HashMap::new(),
+            reverse: HashMap::new(),
+            retracted: vec![],
+        }
+    }
+
+    pub fn build(refs: &[Ref]) -> RefIndex {
+        let mut index = RefIndex::new();
+        let mut facts: HashMap<(usize, &str, usize), Vec<usize>> = HashMap::new();
+
+        for (position, db_ref) in refs.iter().enumerate() {
+            index.forward.entry(db_ref.e).or_insert_with(Vec::new).push(position);
+            index.reverse.entry(db_ref.v).or_insert_with(Vec::new).push(position);
+            facts.entry((db_ref.e, &db_ref.a[..], db_ref.v))
+                 .or_insert_with(Vec::new)
+                 .push(position);
+        }
+
+        index.retracted = vec![None; refs.len()];
+        for (_, fact) in facts {
+            let history = fact.into_iter().map(|p| (p, refs[p].added, refs[p].tx));
+            mark_retractions(history, &mut index.retracted);
+        }
+        index
+    }
+}
+
+fn sorts_with(l: &Row, r: &Row) -> bool {
+    l.e == r.e && l.a == r.a && l.v.total_cmp(&r.v) == Ordering::Equal
+}
+
+// Walks the history of one fact in insertion order, a retraction closes every open assertion
+fn mark_retractions<I>(history: I, retracted: &mut [Option<usize>])
+    where I: Iterator<Item = (usize, bool, usize)>
+{
+    let mut open = vec![];
+    for (position, added, tx) in history {
+        if added {
+            open.push(position);
+        } else {
+            for p in open.drain(..) {
+                retracted[p] = Some(tx);
+            }
+        }
+    }
+}
+
+fn cmp_eavt(l: &Row, r: &Row) -> Ordering {
+    match l.e.cmp(&r.e) {
+        Ordering::Equal => {
+            match l.a.cmp(&r.a) {
+                Ordering::Equal => cmp_vt(l, r),
+                ord => ord,
+            }
+        }
+        ord => ord,
+    }
+}
+
+fn cmp_aevt(l: &Row, r: &Row) -> Ordering {
+    match l.a.cmp(&r.a) {
+        Ordering::Equal => {
+            match l.e.cmp(&r.e) {
+                Ordering::Equal => cmp_vt(l, r),
+                ord => ord,
+            }
+        }
+        ord => ord,
+    }
+}
+
+fn cmp_avet(l: &Row, r: &Row) -> Ordering {
+    match l.a.cmp(&r.a) {
+        Ordering::Equal => {
+            match l.v.total_cmp(&r.v) {
+                Ordering::Equal => {
+                    match l.e.cmp(&r.e) {
+                        Ordering::Equal => l.t.cmp(&r.t),
+                        ord => ord,
+                    }
+                }
+                ord => ord,
+            }
+        }
+        ord => ord,
+    }
+}
+
+fn cmp_vt(l: &Row, r: &Row) -> Ordering {
+    match l.v.total_cmp(&r.v) {
+        Ordering::Equal => l.t.cmp(&r.t),
+        ord => ord,
+    }
+}
+
+fn rangeable<T>(preds: &[(T, Comparator)]) -> Vec<&(T, Comparator)> {
+    preds.iter()
+         .filter(|&&(_, ref comp)| *comp != Comparator::Contains)
+         .collect()
+}
+
+// Where a field sits relative to the values allowed by `preds`: `Less` when it sorts before
+// them, `Greater` when it sorts after and `Equal` when it is inside the range.
+fn probe<T, F>(preds: &[&(T, Comparator)], cmp: F) -> Ordering
+    where F: Fn(&T) -> Ordering
+{
+    let mut result = Ordering::Equal;
+
+    for &&(ref bound, ref comp) in preds {
+        let ord = match (comp, cmp(bound)) {
+            (&Comparator::Equal, ord) => ord,
+            (&Comparator::Greater, Ordering::Greater) => Ordering::Equal,
+            (&Comparator::Greater, _) => Ordering::Less,
+            (&Comparator::GreaterOrEqual, Ordering::Less) => Ordering::Less,
+            (&Comparator::GreaterOrEqual, _) => Ordering::Equal,
+            (&Comparator::Less, Ordering::Less) => Ordering::Equal,
+            (&Comparator::Less, _) => Ordering::Greater,
+            (&Comparator::LessOrEqual, Ordering::Greater) => Ordering::Greater,
+            (&Comparator::LessOrEqual, _) => Ordering::Equal,
+            (&Comparator::Contains, _) => Ordering::Equal,
+        };
+
+        match ord {
+            Ordering::Less => return Ordering::Less,
+            Ordering::Greater => result = Ordering::Greater,
+            Ordering::Equal => (),
+        }
+    }
+    result
+}
+
+// The positions in `index` that `probe` finds inside the wanted values
+fn matching<F>(index: &Column, probe: &F) -> Vec<usize>
+    where F: Fn(usize) -> Ordering
+{
+    let (lower, upper) = range(index, probe);
+    if lower < upper {
+        index.slice(lower, upper)
+    } else {
+        vec![]
+    }
+}
+
+// Merges two sorted runs of positions, those in `left` come first so they go first on ties
+fn merge(datums: &Storage, left: &[usize], right: &[usize], cmp: fn(&Row, &Row) -> Ordering)
+         -> Vec<usize> {
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        if cmp(&datums.row(right[j]), &datums.row(left[i])) == Ordering::Less {
+            merged.push(right[j]);
+            j += 1;
+        } else {
+            merged.push(left[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+    merged
+}
+
+// The range of `index` whose datum positions `probe` finds inside the wanted values
+fn range<F>(index: &Column, probe: F) -> (usize, usize)
+    where F: Fn(usize) -> Ordering
+{
+    let lower = partition_point(index, |p| probe(p) == Ordering::Less);
+    let upper = partition_point(index, |p| probe(p) != Ordering::Greater);
+    (lower, upper)
+}
+
+// First position in `index` for which `pred` is false, `pred` must hold for a prefix only
+fn partition_point<F>(index: &Column, pred: F) -> usize
+    where F: Fn(usize) -> bool
+{
+    let (mut lo, mut hi) = (0, index.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(index.get(mid)) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::f64;
+
+    use super::{Indexes, IndexKind, RefIndex};
+    use ast::AstNode;
+    use data::{Datum, Ref, Value};
+    use storage::Storage;
+
+    fn scan(datums: &[Datum], query: &str) -> Option<(IndexKind, Vec<usize>)> {
+        let datums = Storage::from(datums.to_vec());
+        let indexes = Indexes::build(&datums);
+        match AstNode::parse(query).unwrap() {
+            AstNode::Expression(ref preds) => indexes.scan(&datums, preds),
+            _ => panic!("expected an expression"),
+        }
+    }
+
+    fn gdp() -> Vec<Datum> {
+        vec![Datum::new(1, "gdp/country_name", "Canada", 1970),
+             Datum::new(1, "gdp/value", Value::Float(87896095224.0), 1970),
+             Datum::new(2, "gdp/country_name", "Chile", 1970),
+             Datum::new(2, "gdp/value", Value::Int(8936079253), 1970),
+             Datum::new(3, "gdp/country_name", "Aruba", 1994),
+             Datum::new(3, "gdp/value", Value::Int(99), 1994)]
+    }
+
+    #[test]
+    fn scan_entity() {
+        assert_eq!(Some((IndexKind::Eavt, vec![2, 3])), scan(&gdp(), "e=2"));
+        assert_eq!(Some((IndexKind::Eavt, vec![0, 1, 2, 3])), scan(&gdp(), "e>=1 e<3"));
+        assert_eq!(Some((IndexKind::Eavt, vec![])), scan(&gdp(), "e>3"));
+    }
+
+    #[test]
+    fn scan_attribute() {
+        assert_eq!(Some((IndexKind::Aevt, vec![0, 2, 4])), scan(&gdp(), "a=gdp/country_name"));
+        assert_eq!(Some((IndexKind::Aevt, vec![1, 3, 5])), scan(&gdp(), "a>gdp/country_name"));
+    }
+
+    #[test]
+    fn scan_attribute_value() {
+        assert_eq!(Some((IndexKind::Avet, vec![1, 3])), scan(&gdp(), "a=gdp/value v>1000"));
+        assert_eq!(Some((IndexKind::Avet, vec![2])), scan(&gdp(), "a=gdp/country_name v=Chile"));
+    }
+
+    #[test]
+    fn scan_requires_full_scan() {
+        assert_eq!(None, scan(&gdp(), "a:gdp v>1000"));
+        assert_eq!(None, scan(&gdp(), "t=1970"));
+    }
+
+    #[test]
+    fn sort_nan_last() {
+        let mut datums = vec![Datum::new(4, "gdp/value", Value::Float(f64::NAN), 1994)];
+        datums.extend(gdp());
+        let datums = Storage::from(datums);
+        let indexes = Indexes::build(&datums);
+        assert_eq!(vec![5, 1, 3, 6, 4, 2, 0], indexes.sorted(&datums, IndexKind::Avet));
+        assert_eq!(vec![6], indexes.holding(&datums, "gdp/value", &Value::Int(99)));
+    }
+
+    #[test]
+    fn scan_entities() {
+        let datums = Storage::from(gdp());
+        let indexes = Indexes::build(&datums);
+        let eids = vec![3, 1].into_iter().collect::<HashSet<usize>>();
+        assert_eq!(vec![0, 1, 4, 5], indexes.entities(&datums, &eids));
+    }
+
+    #[test]
+    fn ref_adjacency() {
+        let refs = vec![Ref::new(5, "track/album".to_owned(), 3, 1967),
+                        Ref::new(5, "track/artist".to_owned(), 1, 1967),
+                        Ref::new(3, "album/artist".to_owned(), 1, 1967)];
+        let index = RefIndex::build(&refs);
+        assert_eq!(Some(&vec![0, 1]), index.forward.get(&5));
+        assert_eq!(Some(&vec![1, 2]), index.reverse.get(&1));
+        assert_eq!(None, index.reverse.get(&5));
+    }
+
+    #[test]
+    fn retracted_positions() {
+        let mut datums = gdp();
+        let mut retraction = datums[3].clone();
+        retraction.tx = 1;
+        retraction.added = false;
+        let mut reassertion = datums[3].clone();
+        reassertion.tx = 2;
+        datums.push(retraction);
+        datums.push(reassertion);
+
+        let indexes = Indexes::build(&Storage::from(datums));
+        assert_eq!(vec![None, None, None, Some(1), None, None, None, None],
+                   (0..8).map(|p| indexes.retraction(p)).collect::<Vec<Option<usize>>>());
+    }
+
+    #[test]
+    fn retract_same_fact_only() {
+        let mut datums = vec![Datum::new(1, "gdp/value", Value::Int(1), 1970),
+                              Datum::new(1, "gdp/value", Value::Float(1.0), 1971)];
+        let mut retraction = datums[0].clone();
+        retraction.t = 1972;
+        retraction.tx = 1;
+        retraction.added = false;
+        datums.push(retraction);
+
+        let built = Indexes::build(&Storage::from(datums.clone()));
+        let mut storage = Storage::from(datums[..2].to_vec());
+        let mut appended = Indexes::build(&storage);
+        storage.extend(datums[2..].to_vec());
+        appended.append(&storage, 2);
+        for indexes in &[built, appended] {
+            assert_eq!(vec![Some(1), None], vec![indexes.retraction(0), indexes.retraction(1)]);
+        }
+    }
+
+    #[test]
+    fn append_positions() {
+        let mut datums = gdp();
+        let mut retraction = datums[1].clone();
+        retraction.tx = 1;
+        retraction.added = false;
+        datums.push(retraction);
+        datums.push(Datum::new(4, "gdp/country_name", "Brazil", 2000));
+
+        let mut storage = Storage::from(datums[..4].to_vec());
+        let mut appended = Indexes::build(&storage);
+        storage.extend(datums[4..].to_vec());
+        appended.append(&storage, 4);
+
+        let built = Indexes::build(&storage);
+        for &kind in &[IndexKind::Eavt, IndexKind::Aevt, IndexKind::Avet] {
+            assert_eq!(built.sorted(&storage, kind), appended.sorted(&storage, kind));
+        }
+        assert_eq!(Some(1), appended.retraction(1));
+        assert_eq!(built, appended);
+        assert_eq!(vec![1, 3, 5, 6], appended.attribute(&storage, "gdp/value"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use data::Value;
+use storage::{bytes, decode_value, encode_value, read_u64, well_formed, MappedFile};
+
+/// Interned strings, like attribute names, each given the next id the first time it's seen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbols {
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::from(vec![])
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).cloned()
+    }
+
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+        let id = self.names.len() as u32;
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        id
+    }
+
+    pub fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+
+    /// Every name, in id order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+impl From<Vec<String>> for Symbols {
+    fn from(names: Vec<String>) -> Symbols {
+        let ids = names.iter().enumerate().map(|(id, n)| (n.clone(), id as u32)).collect();
+        Symbols {
+            names: names,
+            ids: ids,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MappedValues {
+    map: Arc<MappedFile>,
+    offsets: usize,
+    values: usize,
+    end: usize,
+    len: usize,
+}
+
+impl MappedValues {
+    // A damaged value reads as a null
+    fn encoded(&self, id: u32) -> &[u8] {
+        let bytes = bytes(&self.map);
+        let start = read_u64(bytes, self.offsets + 8 * id as usize) as usize;
+        let end = read_u64(bytes, self.offsets + 8 * (id as usize + 1)) as usize;
+        if start > end || end > self.end - self.values {
+            self.map.damage("value offsets");
+            return &[6];
+        }
+        let encoded = &bytes[self.values + start..self.values + end];
+        if !well_formed(encoded) {
+            self.map.damage("values");
+            return &[6];
+        }
+        encoded
+    }
+
+    // Values are sorted by their encoding, so ids can be found by bisection
+    fn find(&self, encoded: &[u8]) -> Option<u32> {
+        let (mut lo, mut hi) = (0, self.len as u32);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.encoded(mid).cmp(encoded) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+}
+
+/// Distinct values by id. Values read from a file stay mapped, later ones are kept on the heap
+/// with ids following the mapped ones.
+#[derive(Clone)]
+pub struct Dictionary {
+    mapped: Option<MappedValues>,
+    heap: Vec<Value>,
+    ids: HashMap<Vec<u8>, u32>,
+}
+
+impl Dictionary {
+    pub fn new() -> Dictionary {
+        Dictionary {
+            mapped: None,
+            heap: vec![],
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Reads `len` values from `map`, sorted by their encoding. Value `id` is encoded between
+    /// the 8 byte positions `id` and `id + 1` at `offsets`, relative to the values section
+    /// from `values` to `end`.
+    pub fn mapped(map: Arc<MappedFile>, offsets: usize, values: usize, end: usize, len: usize)
+                  -> Dictionary {
+        let mapped = MappedValues {
+            map: map,
+            offsets: offsets,
+            values: values,
+            end: end,
+            len: len,
+        };
+        Dictionary { mapped: Some(mapped), ..Dictionary::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mapped_len() + self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn value(&self, id: u32) -> Cow<Value> {
+        match self.mapped {
+            Some(ref mapped) if (id as usize) < mapped.len => {
+                Cow::Owned(decode_value(mapped.encoded(id)))
+            }
+            _ => Cow::Borrowed(&self.heap[id as usize - self.mapped_len()]),
+        }
+    }
+
+    /// The id of a value identical to `value`, it has to have the same type as well.
+    pub fn get(&self, value: &Value) -> Option<u32> {
+        let mut encoded = vec![];
+        encode_value(value, &mut encoded);
+        self.find(&encoded)
+    }
+
+    pub fn intern(&mut self, value: &Value) -> u32 {
+        let mut encoded = vec![];
+        encode_value(value, &mut encoded);
+        if let Some(id) = self.find(&encoded) {
+            return id;
+        }
+
+        let id = self.len() as u32;
+        self.heap.push(value.clone());
+        self.ids.insert(encoded, id);
+        id
+    }
+
+    fn find(&self, encoded: &[u8]) -> Option<u32> {
+        self.mapped
+            .as_ref()
+            .and_then(|m| m.find(encoded))
+            .or_else(|| self.ids.get(encoded).cloned())
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.mapped.as_ref().map_or(0, |m| m.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dictionary, Symbols};
+    use data::Value;
+
+    #[test]
+    fn intern_symbols() {
+        let mut symbols = Symbols::new();
+        assert_eq!(0, symbols.intern("gdp/country_name"));
+        assert_eq!(1, symbols.intern("gdp/value"));
+        assert_eq!(0, symbols.intern("gdp/country_name"));
+        assert_eq!(Some(1), symbols.get("gdp/value"));
+        assert_eq!(None, symbols.get("gdp/year"));
+        assert_eq!("gdp/value", symbols.name(1));
+    }
+
+    #[test]
+    fn intern_values() {
+        let mut values = Dictionary::new();
+        assert_eq!(0, values.intern(&Value::from("Canada")));
+        assert_eq!(1, values.intern(&Value::Int(1970)));
+        assert_eq!(0, values.intern(&Value::from("Canada")));
+        assert_eq!(None, values.get(&Value::Float(1970.0)));
+        assert_eq!(Value::Int(1970), *values.value(1));
+        assert_eq!(2, values.len());
+    }
+}
@@ -17,74 +17,189 @@ extern crate linenoise;
 extern crate flate2;
 extern crate time;
 extern crate scoped_threadpool;
+extern crate memmap;
 
 mod data;
+mod aggregate;
 mod ast;
 mod cli;
 mod plan;
 mod filter;
+mod index;
+mod intern;
 mod csv_parser;
+mod format;
+mod project;
+mod schema;
+mod storage;
+mod table;
+mod wal;
+#[cfg(test)]
+mod temp_file;
 
+use std::env;
 use std::process;
 use scoped_threadpool::Pool;
 
-use ast::AstNode;
+use ast::Query;
 use cli::CliCommand;
 use csv_parser::CsvParser;
-use data::Db;
+use data::{Datum, Error};
 use filter::Filter;
 use plan::Plan;
+use storage::Row;
+use wal::Wal;
 
 peg_file! grammar("grammar.rustpeg");
 
+const DEFAULT_DB: &'static str = "entity-query.db";
+
 fn main() {
     linenoise::history_set_max_len(1000);
     linenoise::history_load(".history");
 
-    let mut db = Db::new();
+    // Every change is logged next to this snapshot and replayed on the next start
+    let path = env::args().nth(1).unwrap_or(DEFAULT_DB.to_owned());
+    let (mut db, mut wal) = match Wal::open(&path) {
+        Ok(opened) => opened,
+        Err(e) => {
+            println!("{:?}", e);
+            process::exit(1)
+        }
+    };
     let mut pool = Pool::new(12);
 
     loop {
         println!("size: {}", db.datums.len());
         match cli::read() {
             Ok(CliCommand::Query(query)) => {
-                match AstNode::parse(&query) {
-                    Ok(ast) => {
-                        println!("new_plan: {:?}", Plan::new(&ast));
-                        println!("ast: {:?}", ast);
+                match Query::parse(&query) {
+                    Ok(query) => {
                         let start = time::precise_time_s();
-                        let res = Filter::new(&db, &mut pool).execute(&ast);
+                        let mut res = Filter::with_basis(&db, &mut pool, query.basis)
+                                          .execute(&query.ast);
+                        if let Err(e) = db.check() {
+                            println!("{:?}", e);
+                            continue;
+                        }
+                        query.sort(&mut res.datums);
                         println!("duration: {}", time::precise_time_s() - start);
                         println!("len: {}", res.datums.len());
-                        println!("{}", res)
+                        if query.projection.is_empty() {
+                            let lines = query.page(res.datums)
+                                             .iter()
+                                             .map(|d| d.to_string())
+                                             .collect::<Vec<String>>();
+                            if lines.is_empty() {
+                                println!("()")
+                            } else {
+                                cli::page(&lines.join("\n"))
+                            }
+                        } else {
+                            match project::project(&db, &res, &query) {
+                                Ok(mut table) => {
+                                    table.rows = query.page(table.rows);
+                                    cli::page_table(&table)
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                    }
+                    Err(e) => println!("{:?}", e),
+                }
+            }
+            Ok(CliCommand::Explain(query)) => {
+                match Query::parse(&query) {
+                    Ok(query) => println!("{}", Plan::new(&query.ast)),
+                    Err(e) => println!("{:?}", e),
+                }
+            }
+            Ok(CliCommand::Analyze(query)) => {
+                match Query::parse(&query) {
+                    Ok(query) => {
+                        println!("{}", Plan::new(&query.ast));
+                        let start = time::precise_time_s();
+                        let (res, stats) = Filter::with_basis(&db, &mut pool, query.basis)
+                                               .analyze(&query.ast);
+                        for stage in &stats {
+                            println!("{}", stage);
+                        }
+                        println!("duration: {}", time::precise_time_s() - start);
+                        println!("len: {}", res.datums.len())
+                    }
+                    Err(e) => println!("{:?}", e),
+                }
+            }
+            Ok(CliCommand::Retract(query, note)) => {
+                match Query::parse(&query) {
+                    Ok(query) => {
+                        let datums = Filter::new(&db, &mut pool)
+                                         .execute(&query.ast)
+                                         .datums
+                                         .iter()
+                                         .map(Row::to_datum)
+                                         .collect::<Vec<Datum>>();
+                        println!("retracted: {}", datums.len());
+                        let commit = db.prepare_retract(datums, vec![], note);
+                        match wal.commit(&mut db, commit) {
+                            Ok(tx) => println!("{}", db.transactions[tx]),
+                            Err(e) => println!("{:?}", e),
+                        }
                     }
                     Err(e) => println!("{:?}", e),
                 }
             }
             Ok(CliCommand::Load(filename)) => {
                 let start = time::precise_time_s();
-                db = Db::new(); // de-alloc the old Db
-                match Db::from_file(&filename) {
-                    Ok(d) => {
+                // The session moves on to the loaded file and its log, the file it leaves is
+                // brought up to date first
+                if let Err(e) = wal.checkpoint(&mut db) {
+                    println!("{:?}", e);
+                }
+                match Wal::open(&filename) {
+                    Ok((d, w)) => {
                         db = d;
+                        wal = w;
                         println!("duration: {}", time::precise_time_s() - start);
                         println!("len: {}", db.datums.len());
-                        println!("{}", db)
+                        println!("{}", db);
                     }
                     Err(e) => println!("{:?}", e),
                 }
             }
-            Ok(CliCommand::LoadCsv(filename, entity, time, joins)) => {
+            Ok(CliCommand::LoadCsv(filename, entity, time, options, note)) => {
                 let start = time::precise_time_s();
-                let parser = CsvParser::new(&filename, &entity, &time, &joins);
+                let parser = CsvParser::new(&filename, &entity, &time, &options);
 
                 match parser.parse(&db, &mut pool) {
-                    Ok((datums, refs, offset)) => {
-                        println!("new: {}", datums.len());
-                        db.insert(datums, refs, offset);
+                    Ok(import) => {
+                        let skipped = import.skipped
+                                            .iter()
+                                            .map(|err| err.to_string())
+                                            .collect::<Vec<String>>();
+                        cli::page(&skipped.join("\n"));
+                        println!("{}", import);
+                        println!("new: {}", import.datums.len());
+                        let commit = db.prepare_upsert(import.datums,
+                                                       import.refs,
+                                                       import.offset,
+                                                       Some(filename.clone()),
+                                                       note);
+                        match commit.and_then(|commit| wal.commit(&mut db, commit)) {
+                            Ok(tx) => println!("{}", db.transactions[tx]),
+                            Err(e) => println!("{:?}", e),
+                        }
                         println!("duration: {}", time::precise_time_s() - start);
                         println!("{}", db)
                     }
+                    Err(e) => cli::page(&import_error(e)),
+                }
+            }
+            Ok(CliCommand::Schema) => cli::page_table(&db.schema.table()),
+            Ok(CliCommand::Declare(attribute)) => {
+                let commit = db.prepare_declare(attribute, None);
+                match commit.and_then(|commit| wal.commit(&mut db, commit)) {
+                    Ok(tx) => println!("{}", db.transactions[tx]),
                     Err(e) => println!("{:?}", e),
                 }
             }
@@ -94,10 +209,33 @@ fn main() {
                     Err(e) => println!("{:?}", e),
                 }
             }
-            Ok(CliCommand::Empty) => db = Db::new(),
+            Ok(CliCommand::Empty) => {
+                if let Err(e) = wal.reset(&mut db) {
+                    println!("{:?}", e);
+                }
+            }
             Ok(CliCommand::None) => continue,
-            Ok(CliCommand::Exit) => process::exit(0),
+            Ok(CliCommand::Exit) => {
+                if let Err(e) = wal.checkpoint(&mut db) {
+                    println!("{:?}", e)
+                }
+                process::exit(0)
+            }
             Err(e) => println!("{:?}", e),
         }
     }
 }
+
+// Rows and columns that fail a load are reported the way skipped rows are listed
+fn import_error(err: Error) -> String {
+    match err {
+        Error::Row(err) => err.to_string(),
+        Error::NonConforming(mismatches) => {
+            mismatches.iter()
+                      .map(|m| m.to_string())
+                      .collect::<Vec<String>>()
+                      .join("\n")
+        }
+        err => format!("{:?}", err),
+    }
+}
@@ -1,11 +1,31 @@
 use std::collections::HashMap;
-use ast::{AstNode, Predicates};
+use std::fmt;
+use ast::{AstNode, Predicates, RefPath};
 
 #[derive(Debug, PartialEq)]
 pub enum IndexedNode {
     Base(Predicates),
     Or(usize, usize),
+    And(usize, usize),
+    Not(usize),
     Join(Predicates, usize),
+    RefJoin(Predicates, RefPath, usize),
+}
+
+impl fmt::Display for IndexedNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexedNode::Base(ref p) if p.is_empty() => write!(f, "scan"),
+            IndexedNode::Base(ref p) => write!(f, "scan {}", p),
+            IndexedNode::Join(ref p, _) if p.is_empty() => write!(f, "join"),
+            IndexedNode::Join(ref p, _) => write!(f, "join {}", p),
+            IndexedNode::RefJoin(ref p, ref path, _) if p.is_empty() => write!(f, "join {}", path),
+            IndexedNode::RefJoin(ref p, ref path, _) => write!(f, "join {} {}", path, p),
+            IndexedNode::Or(_, _) => write!(f, "or"),
+            IndexedNode::And(_, _) => write!(f, "and"),
+            IndexedNode::Not(_) => write!(f, "not"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -29,7 +49,9 @@ impl Plan {
 
             match indexed {
                 IndexedNode::Base(_) => stages[0].push(id),
-                IndexedNode::Join(_, c) => {
+                IndexedNode::Join(_, c) |
+                IndexedNode::RefJoin(_, _, c) |
+                IndexedNode::Not(c) => {
                     let child_idx = find_stage_idx(&stages, c);
                     let stage_idx = child_idx + 1;
 
@@ -38,7 +60,7 @@ impl Plan {
                     }
                     stages[stage_idx].push(id);
                 }
-                IndexedNode::Or(l, r) => {
+                IndexedNode::Or(l, r) | IndexedNode::And(l, r) => {
                     let left_idx = find_stage_idx(&stages, l);
                     let right_idx = find_stage_idx(&stages, r);
                     let stage_idx = if left_idx > right_idx { left_idx + 1 } else { right_idx + 1 };
@@ -58,6 +80,53 @@ impl Plan {
             stages: stages,
         }
     }
+
+    pub fn stages(&self) -> &[Vec<usize>] {
+        &self.stages
+    }
+
+    pub fn task(&self, id: usize) -> &IndexedNode {
+        &self.tasks[&id]
+    }
+
+    /// The task producing the result of the whole query, it always has the highest id.
+    pub fn root(&self) -> usize {
+        self.tasks.len() - 1
+    }
+
+    fn fmt_task(&self, f: &mut fmt::Formatter, id: usize, lead: &str, indent: &str)
+                -> fmt::Result {
+        try!(write!(f,
+                    "{}#{} {} (stage {})",
+                    lead,
+                    id,
+                    self.tasks[&id],
+                    find_stage_idx(&self.stages, id)));
+
+        let children = match self.tasks[&id] {
+            IndexedNode::Base(_) => vec![],
+            IndexedNode::Join(_, c) |
+            IndexedNode::RefJoin(_, _, c) |
+            IndexedNode::Not(c) => vec![c],
+            IndexedNode::Or(l, r) | IndexedNode::And(l, r) => vec![l, r],
+        };
+
+        for (i, &child) in children.iter().enumerate() {
+            let last = i == children.len() - 1;
+            let child_lead = format!("{}{}", indent, if last { "└── " } else { "├── " });
+            let child_indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+
+            try!(write!(f, "\n"));
+            try!(self.fmt_task(f, child, &child_lead, &child_indent));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_task(f, self.root(), "", "")
+    }
 }
 
 fn find_stage_idx(stages: &[Vec<usize>], id: usize) -> usize {
@@ -71,18 +140,30 @@ fn find_stage_idx(stages: &[Vec<usize>], id: usize) -> usize {
 
 fn index_node(ast: &AstNode, nodes: &[(usize, &AstNode)]) -> IndexedNode {
     match *ast {
-        AstNode::True => IndexedNode::Base(Predicates::new(None, None, None, None)),
+        AstNode::True => IndexedNode::Base(Predicates::empty()),
         AstNode::Expression(ref p) => IndexedNode::Base(p.clone()),
         AstNode::Join(ref p, ref c) => {
             let &(child_idx, _) = nodes.iter().find(|e| *e.1 == **c).unwrap();
             IndexedNode::Join(p.clone(), child_idx)
         }
+        AstNode::RefJoin(ref p, ref path, ref c) => {
+            let &(child_idx, _) = nodes.iter().find(|e| *e.1 == **c).unwrap();
+            IndexedNode::RefJoin(p.clone(), path.clone(), child_idx)
+        }
         AstNode::Or(ref l, ref r) => {
             let &(left_idx, _) = nodes.iter().find(|e| *e.1 == **l).unwrap();
             let &(right_idx, _) = nodes.iter().find(|e| *e.1 == **r).unwrap();
             IndexedNode::Or(left_idx, right_idx)
         }
-        _ => panic!(),
+        AstNode::And(ref l, ref r) => {
+            let &(left_idx, _) = nodes.iter().find(|e| *e.1 == **l).unwrap();
+            let &(right_idx, _) = nodes.iter().find(|e| *e.1 == **r).unwrap();
+            IndexedNode::And(left_idx, right_idx)
+        }
+        AstNode::Not(ref c) => {
+            let &(child_idx, _) = nodes.iter().find(|e| *e.1 == **c).unwrap();
+            IndexedNode::Not(child_idx)
+        }
     }
 }
 
@@ -93,18 +174,17 @@ fn split_nodes(ast: &AstNode) -> Vec<&AstNode> {
             match *ast {
                 AstNode::True => vec![ast],
                 AstNode::Expression(_) => vec![ast],
-                AstNode::Or(ref l, ref r) => {
+                AstNode::Or(ref l, ref r) | AstNode::And(ref l, ref r) => {
                     let mut ns = vec![ast];
                     ns.extend(split_nodes(l));
                     ns.extend(split_nodes(r));
                     ns
                 }
-                AstNode::Join(_, ref c) => {
+                AstNode::Join(_, ref c) | AstNode::RefJoin(_, _, ref c) | AstNode::Not(ref c) => {
                     let mut ns = vec![ast];
                     ns.extend(split_nodes(c));
                     ns
                 }
-                _ => panic!(),
             }
         })
         .collect()
@@ -112,7 +192,7 @@ fn split_nodes(ast: &AstNode) -> Vec<&AstNode> {
 
 #[cfg(test)]
 mod tests {
-    use super::Plan;
+    use super::{IndexedNode, Plan};
     use ast::AstNode;
 
     #[test]
@@ -135,4 +215,38 @@ mod tests {
         let plan = Plan::new(&ast);
         assert_eq!(vec![vec![0, 2], vec![1, 3], vec![4]], plan.stages)
     }
+
+    #[test]
+    fn and_not_query() {
+        let ast = AstNode::parse("a=track/artist !(v=Queen)").unwrap();
+        let plan = Plan::new(&ast);
+        assert_eq!(vec![vec![0, 2], vec![1], vec![3]], plan.stages)
+    }
+
+    #[test]
+    fn root_task() {
+        let ast = AstNode::parse("e:(e=1) | e=2").unwrap();
+        let plan = Plan::new(&ast);
+        assert_eq!(3, plan.root());
+        assert_eq!(&IndexedNode::Or(2, 0), plan.task(plan.root()))
+    }
+
+    #[test]
+    fn display_tree() {
+        let ast = AstNode::parse("e:(v:Led) | e=2").unwrap();
+        let plan = Plan::new(&ast);
+        let expected = "#3 or (stage 2)\n\
+                        ├── #2 join (stage 1)\n\
+                        │   └── #1 scan v:Led (stage 0)\n\
+                        └── #0 scan e=2 (stage 0)";
+        assert_eq!(expected, plan.to_string())
+    }
+
+    #[test]
+    fn ref_join_query() {
+        let ast = AstNode::parse("track/album:(v=IV) | track/_album:(v=Flaming)").unwrap();
+        let plan = Plan::new(&ast);
+        assert_eq!(vec![vec![0, 2], vec![1, 3], vec![4]], plan.stages);
+        assert_eq!("join track/_album", plan.task(1).to_string())
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use ast::{Column, Field, Query, Selection};
+use data::{Basis, Db, DbView, Value};
+use storage::{encode_value, Row};
+use table::{Cell, Table};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotGrouped(Field),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotGrouped(ref field) => {
+                write!(f, "{} must be aggregated or appear in the by clause", field)
+            }
+        }
+    }
+}
+
+/// Builds the table for the projection of `query`. Rows come from each entity in `view`, in
+/// order of first appearance, unless a datum field (`a`, `v`, `t` or `tx`) is selected, then
+/// every datum gets a row. Aggregates fold these rows into one per distinct value of the `by`
+/// fields.
+pub fn project(db: &Db, view: &DbView, query: &Query) -> Result<Table, Error> {
+    let per_datum = query.projection.iter().any(|s| s.field().is_datum_field()) ||
+                    query.group_by.iter().any(|f| f.is_datum_field());
+
+    let mut seen = HashSet::new();
+    let sources = view.datums
+                      .iter()
+                      .filter(|d| per_datum || seen.insert(d.e))
+                      .collect::<Vec<&Row>>();
+
+    if query.is_aggregate() {
+        aggregate(db, &sources, query)
+    } else {
+        let mut names = vec![];
+        if !per_datum {
+            names.push("e".to_owned());
+        }
+        names.extend(query.projection.iter().map(|s| s.to_string()));
+
+        let mut table = Table::new(names);
+        for datum in sources {
+            let mut row = vec![];
+            if !per_datum {
+                row.push(vec![Value::Int(datum.e as i64)]);
+            }
+            row.extend(query.projection.iter().map(|s| value(db, datum, s.field(), query.basis)));
+            table.rows.push(row);
+        }
+        Ok(table)
+    }
+}
+
+fn aggregate(db: &Db, sources: &[&Row], query: &Query) -> Result<Table, Error> {
+    for selection in &query.projection {
+        if let Selection::Field(ref field) = *selection {
+            if !query.group_by.contains(field) {
+                return Err(Error::NotGrouped(field.clone()));
+            }
+        }
+    }
+
+    let mut names = query.group_by.iter().map(|f| f.to_string()).collect::<Vec<String>>();
+    names.extend(query.projection.iter().map(|s| s.to_string()));
+
+    // Groups in order of first appearance, with the values collected for each selection
+    let mut groups: Vec<(Vec<Cell>, Vec<Vec<Value>>)> = vec![];
+    let mut positions: HashMap<Vec<Vec<Vec<u8>>>, usize> = HashMap::new();
+
+    if query.group_by.is_empty() {
+        groups.push((vec![], vec![vec![]; query.projection.len()]));
+        positions.insert(vec![], 0);
+    }
+
+    for datum in sources {
+        let keys = query.group_by
+                        .iter()
+                        .map(|f| value(db, datum, f, query.basis))
+                        .collect::<Vec<Cell>>();
+        let key = keys.iter().map(encode_cell).collect::<Vec<Vec<Vec<u8>>>>();
+
+        let position = match positions.get(&key) {
+            Some(&p) => p,
+            None => {
+                groups.push((keys, vec![vec![]; query.projection.len()]));
+                groups.len() - 1
+            }
+        };
+        positions.insert(key, position);
+
+        for (i, selection) in query.projection.iter().enumerate() {
+            if let Selection::Aggregate(_, ref field) = *selection {
+                groups[position].1[i].extend(value(db, datum, field, query.basis));
+            }
+        }
+    }
+
+    let mut table = Table::new(names);
+    for (keys, values) in groups {
+        let mut row = keys.clone();
+        for (selection, values) in query.projection.iter().zip(values) {
+            match *selection {
+                Selection::Aggregate(agg, _) => row.push(agg.apply(&values)),
+                Selection::Field(ref field) => {
+                    let i = query.group_by.iter().position(|f| f == field).unwrap();
+                    row.push(keys[i].clone());
+                }
+            }
+        }
+        table.rows.push(row);
+    }
+    Ok(table)
+}
+
+// Encodes each value of a cell like storage does, values hold floats and can't be hashed
+fn encode_cell(cell: &Cell) -> Vec<Vec<u8>> {
+    cell.iter()
+        .map(|v| {
+            let mut bytes = vec![];
+            encode_value(v, &mut bytes);
+            bytes
+        })
+        .collect()
+}
+
+fn value(db: &Db, datum: &Row, field: &Field, basis: Basis) -> Cell {
+    match *field {
+        Field::E => vec![Value::Int(datum.e as i64)],
+        Field::A => vec![Value::from(datum.a)],
+        Field::V => vec![datum.v.clone().into_owned()],
+        Field::T => vec![Value::Timestamp(datum.t)],
+        Field::Tx => vec![Value::Int(datum.tx as i64)],
+        Field::Attribute(ref column) => resolve(db, datum.e, column, basis),
+    }
+}
+
+fn resolve(db: &Db, eid: usize, column: &Column, basis: Basis) -> Cell {
+    let mut eids = HashSet::new();
+    eids.insert(eid);
+
+    for path in &column.refs {
+        eids = if path.reverse {
+            db.referrers(&eids, &path.attribute, basis)
+        } else {
+            db.referenced(&eids, &path.attribute, basis)
+        };
+    }
+
+    db.indexes
+      .entities(&db.datums, &eids)
+      .into_iter()
+      .filter(|&p| db.visible(p, basis))
+      .map(|p| db.datums.row(p))
+      .filter(|d| d.a == column.attribute)
+      .map(|d| d.v.into_owned())
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use scoped_threadpool::Pool;
+
+    use super::{project, Error};
+    use ast::{Field, Query};
+    use data::{year, Datum, Db, Ref, Value};
+    use filter::Filter;
+
+    #[test]
+    fn project_entities() {
+        let datums = vec![Datum::new(1, "album/name", "Led Zeppelin II", year(1969)),
+                          Datum::new(2, "track/name", "Thank You", year(1969)),
+                          Datum::new(2, "track/length", Value::Int(290), year(1969)),
+                          Datum::new(3, "track/name", "The Lemon Song", year(1969))];
+        let refs = vec![Ref::new(2, "track/album".to_owned(), 1, year(1969)),
+                        Ref::new(3, "track/album".to_owned(), 1, year(1969))];
+
+        let mut db = Db::new();
+        db.insert(datums, refs, 3).unwrap();
+        let mut pool = Pool::new(1);
+
+        let query = Query::parse("a:track => track/length track/album.album/name").unwrap();
+        let view = Filter::new(&db, &mut pool).execute(&query.ast);
+        let table = project(&db, &view, &query).unwrap();
+
+        assert_eq!(vec!["e", "track/length", "track/album.album/name"], table.columns);
+        assert_eq!(vec![vec![vec![Value::Int(2)],
+                             vec![Value::Int(290)],
+                             vec![Value::from("Led Zeppelin II")]],
+                        vec![vec![Value::Int(3)], vec![], vec![Value::from("Led Zeppelin II")]]],
+                   table.rows);
+    }
+
+    #[test]
+    fn project_aggregates() {
+        let datums = vec![Datum::new(1, "album/name", "Led Zeppelin II", year(1969)),
+                          Datum::new(2, "album/name", "Led Zeppelin IV", year(1971)),
+                          Datum::new(3, "track/length", Value::Int(290), year(1969)),
+                          Datum::new(4, "track/length", Value::Int(334), year(1969)),
+                          Datum::new(5, "track/length", Value::Int(482), year(1971))];
+        let refs = vec![Ref::new(3, "track/album".to_owned(), 1, year(1969)),
+                        Ref::new(4, "track/album".to_owned(), 1, year(1969)),
+                        Ref::new(5, "track/album".to_owned(), 2, year(1971))];
+
+        let mut db = Db::new();
+        db.insert(datums, refs, 5).unwrap();
+        let mut pool = Pool::new(1);
+
+        let query = Query::parse("a=track/length => count(e) sum(v) max(v) by t").unwrap();
+        let view = Filter::new(&db, &mut pool).execute(&query.ast);
+        let table = project(&db, &view, &query).unwrap();
+
+        assert_eq!(vec!["t", "count(e)", "sum(v)", "max(v)"], table.columns);
+        assert_eq!(vec![vec![vec![Value::Timestamp(year(1969))],
+                             vec![Value::Int(2)],
+                             vec![Value::Int(624)],
+                             vec![Value::Int(334)]],
+                        vec![vec![Value::Timestamp(year(1971))],
+                             vec![Value::Int(1)],
+                             vec![Value::Int(482)],
+                             vec![Value::Int(482)]]],
+                   table.rows);
+
+        let query = Query::parse("a:track => count(e) by track/album.album/name").unwrap();
+        let view = Filter::new(&db, &mut pool).execute(&query.ast);
+        let table = project(&db, &view, &query).unwrap();
+
+        assert_eq!(vec![vec![vec![Value::from("Led Zeppelin II")], vec![Value::Int(2)]],
+                        vec![vec![Value::from("Led Zeppelin IV")], vec![Value::Int(1)]]],
+                   table.rows);
+
+        let query = Query::parse("a:track => v count(e)").unwrap();
+        let view = Filter::new(&db, &mut pool).execute(&query.ast);
+        assert_eq!(Err(Error::NotGrouped(Field::V)), project(&db, &view, &query));
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use data::{parse_time, Basis, Datum, Db, Value};
+use storage::encode_value;
+use table::Table;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Timestamp,
+    Ref,
+}
+
+impl ValueType {
+    pub fn parse(raw: &str) -> Option<ValueType> {
+        match raw {
+            "int" => Some(ValueType::Int),
+            "float" => Some(ValueType::Float),
+            "bool" => Some(ValueType::Bool),
+            "str" => Some(ValueType::Str),
+            "timestamp" | "date" => Some(ValueType::Timestamp),
+            "ref" => Some(ValueType::Ref),
+            _ => None,
+        }
+    }
+
+    /// Floats also accept ints, a column of numbers often has both. Every type accepts nulls.
+    pub fn accepts(&self, value: &Value) -> bool {
+        match (*self, value) {
+            (ValueType::Int, &Value::Int(_)) => true,
+            (ValueType::Float, &Value::Float(_)) => true,
+            (ValueType::Float, &Value::Int(_)) => true,
+            (ValueType::Bool, &Value::Bool(_)) => true,
+            (ValueType::Str, &Value::Str(_)) => true,
+            (ValueType::Timestamp, &Value::Timestamp(_)) => true,
+            (ValueType::Ref, &Value::Ref(_)) => true,
+            (_, &Value::Null) => true,
+            _ => false,
+        }
+    }
+
+    /// Converts the text of a cell to a value of this type, `None` if it doesn't conform.
+    pub fn convert(&self, raw: &str) -> Option<Value> {
+        match (*self, Value::parse(raw)) {
+            (ValueType::Int, Value::Int(i)) => Some(Value::Int(i)),
+            (ValueType::Float, Value::Int(i)) => Some(Value::Float(i as f64)),
+            (ValueType::Float, Value::Float(n)) => Some(Value::Float(n)),
+            (ValueType::Bool, _) => {
+                match &*raw.to_lowercase() {
+                    "true" => Some(Value::Bool(true)),
+                    "false" => Some(Value::Bool(false)),
+                    _ => None,
+                }
+            }
+            (ValueType::Str, _) => Some(Value::Str(raw.to_owned())),
+            (ValueType::Timestamp, _) => parse_time(raw).map(Value::Timestamp),
+            (ValueType::Ref, _) => raw.trim_left_matches('#').parse().ok().map(Value::Ref),
+            _ => None,
+        }
+    }
+
+    /// The most specific type all the non-empty `cells` convert to, falling back to `Str`.
+    pub fn infer<'a, I: Iterator<Item = &'a str>>(cells: I) -> ValueType {
+        let mut candidates = vec![ValueType::Int,
+                                  ValueType::Float,
+                                  ValueType::Bool,
+                                  ValueType::Timestamp];
+        let mut seen = false;
+
+        for cell in cells.filter(|c| !c.is_empty()) {
+            seen = true;
+            candidates.retain(|t| t.convert(cell).is_some());
+            if candidates.is_empty() {
+                break;
+            }
+        }
+        match candidates.first() {
+            Some(&value_type) if seen => value_type,
+            _ => ValueType::Str,
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+            ValueType::Str => "str",
+            ValueType::Timestamp => "timestamp",
+            ValueType::Ref => "ref",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cardinality {
+    One,
+    Many,
+}
+
+impl fmt::Display for Cardinality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cardinality::One => write!(f, "one"),
+            Cardinality::Many => write!(f, "many"),
+        }
+    }
+}
+
+/// Values of a unique attribute belong to a single entity. An identity also names the entity,
+/// so imports can find it again by that value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unique {
+    Identity,
+    Value,
+}
+
+impl fmt::Display for Unique {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unique::Identity => write!(f, "identity"),
+            Unique::Value => write!(f, "value"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyDeclared(String),
+    WrongType(String, ValueType, Value),
+    ManyValues(String, usize),
+    NotUnique(String, Value),
+}
+
+/// A declared attribute. Declarations are asserted as `db/` datums about an entity of their
+/// own, like transactions, so they are saved and queried like any other fact.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value_type: ValueType,
+    pub cardinality: Cardinality,
+    pub unique: Option<Unique>,
+    pub doc: Option<String>,
+}
+
+impl Attribute {
+    pub fn new(name: String, value_type: ValueType) -> Attribute {
+        Attribute {
+            name: name,
+            value_type: value_type,
+            cardinality: Cardinality::Many,
+            unique: None,
+            doc: None,
+        }
+    }
+
+    /// Parses a declaration like `artist/name str one identity`, options after the type can be
+    /// given in any order.
+    pub fn parse(args: &[&str], doc: Option<String>) -> Option<Attribute> {
+        if args.len() < 2 {
+            return None;
+        }
+        let mut attribute = match ValueType::parse(args[1]) {
+            Some(value_type) => Attribute::new(args[0].to_owned(), value_type),
+            None => return None,
+        };
+
+        for option in &args[2..] {
+            match *option {
+                "one" => attribute.cardinality = Cardinality::One,
+                "many" => attribute.cardinality = Cardinality::Many,
+                "identity" => attribute.unique = Some(Unique::Identity),
+                "unique" => attribute.unique = Some(Unique::Value),
+                _ => return None,
+            }
+        }
+        attribute.doc = doc;
+        Some(attribute)
+    }
+
+    pub fn datums(&self, e: usize, t: i64) -> Vec<Datum> {
+        let mut datums = vec![Datum::new(e, "db/ident", self.name.clone(), t),
+                              Datum::new(e, "db/type", self.value_type.to_string(), t),
+                              Datum::new(e, "db/cardinality", self.cardinality.to_string(), t)];
+        if let Some(unique) = self.unique {
+            datums.push(Datum::new(e, "db/unique", unique.to_string(), t));
+        }
+        if let Some(ref doc) = self.doc {
+            datums.push(Datum::new(e, "db/doc", doc.clone(), t));
+        }
+        datums
+    }
+}
+
+/// The current declarations, attributes without one accept anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    attributes: BTreeMap<String, Attribute>,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema { attributes: BTreeMap::new() }
+    }
+
+    /// Reads the declarations currently asserted in `db`.
+    pub fn build(db: &Db) -> Schema {
+        let mut schema = Schema::new();
+
+        for p in db.indexes.attribute(&db.datums, "db/ident") {
+            if !db.visible(p, Basis::Current) {
+                continue;
+            }
+            let ident = db.datums.row(p);
+            let mut eids = HashSet::new();
+            eids.insert(ident.e);
+
+            let mut attribute = Attribute::new(ident.v.to_string(), ValueType::Str);
+            for q in db.indexes.entities(&db.datums, &eids) {
+                if !db.visible(q, Basis::Current) {
+                    continue;
+                }
+                let row = db.datums.row(q);
+                match (row.a, &*row.v.as_text()) {
+                    ("db/type", name) => {
+                        attribute.value_type = ValueType::parse(name).unwrap_or(ValueType::Str)
+                    }
+                    ("db/cardinality", "one") => attribute.cardinality = Cardinality::One,
+                    ("db/unique", "identity") => attribute.unique = Some(Unique::Identity),
+                    ("db/unique", "value") => attribute.unique = Some(Unique::Value),
+                    ("db/doc", doc) => attribute.doc = Some(doc.to_owned()),
+                    _ => (),
+                }
+            }
+            schema.attributes.insert(attribute.name.clone(), attribute);
+        }
+        schema
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.get(name)
+    }
+
+    /// Checks `datums`, about to be asserted in `db`, against their declarations. Returns the
+    /// current datums they replace: asserting a value of a cardinality one attribute retracts
+    /// the entity's previous value.
+    pub fn check(&self, db: &Db, datums: &[Datum]) -> Result<Vec<Datum>, Error> {
+        let mut replaced = vec![];
+        let mut one: HashMap<(usize, &str), &Value> = HashMap::new();
+        let mut unique: HashMap<(&str, Vec<u8>), usize> = HashMap::new();
+
+        for datum in datums {
+            let attribute = match self.get(&datum.a) {
+                Some(attribute) => attribute,
+                None => continue,
+            };
+
+            if !attribute.value_type.accepts(&datum.v) {
+                return Err(Error::WrongType(datum.a.clone(),
+                                            attribute.value_type,
+                                            datum.v.clone()));
+            }
+
+            if attribute.cardinality == Cardinality::One {
+                match one.insert((datum.e, &datum.a[..]), &datum.v) {
+                    Some(other) if *other != datum.v => {
+                        return Err(Error::ManyValues(datum.a.clone(), datum.e))
+                    }
+                    Some(_) => (),
+                    None => {
+                        replaced.extend(db.indexes
+                                          .about(&db.datums, datum.e, &datum.a)
+                                          .into_iter()
+                                          .filter(|&p| db.visible(p, Basis::Current))
+                                          .map(|p| db.datums.row(p).to_datum())
+                                          .filter(|d| d.v != datum.v))
+                    }
+                }
+            }
+
+            // Missing values don't name anything
+            if attribute.unique.is_some() && datum.v != Value::Null {
+                let mut key = vec![];
+                encode_value(&datum.v, &mut key);
+                let taken = match unique.insert((&datum.a[..], key), datum.e) {
+                    Some(e) => e != datum.e,
+                    None => {
+                        db.indexes
+                          .holding(&db.datums, &datum.a, &datum.v)
+                          .into_iter()
+                          .any(|p| {
+                              db.visible(p, Basis::Current) && db.datums.fact(p).e != datum.e
+                          })
+                    }
+                };
+                if taken {
+                    return Err(Error::NotUnique(datum.a.clone(), datum.v.clone()));
+                }
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// Checks the datums already asserted about `attribute` before it's declared.
+    pub fn check_declaration(&self, db: &Db, attribute: &Attribute) -> Result<(), Error> {
+        if self.get(&attribute.name).is_some() {
+            return Err(Error::AlreadyDeclared(attribute.name.clone()));
+        }
+
+        let mut declared = Schema::new();
+        declared.attributes.insert(attribute.name.clone(), attribute.clone());
+        let current = db.indexes
+                        .attribute(&db.datums, &attribute.name)
+                        .into_iter()
+                        .filter(|&p| db.visible(p, Basis::Current))
+                        .map(|p| db.datums.row(p).to_datum())
+                        .collect::<Vec<Datum>>();
+        declared.check(&Db::new(), &current).map(|_| ())
+    }
+
+    pub fn table(&self) -> Table {
+        let columns = ["attribute", "type", "cardinality", "unique", "doc"];
+        let mut table = Table::new(columns.iter().map(|c| c.to_string()).collect());
+
+        for attribute in self.attributes.values() {
+            let unique = attribute.unique.iter().map(|u| Value::from(u.to_string())).collect();
+            table.rows.push(vec![vec![Value::from(&attribute.name[..])],
+                                 vec![Value::from(attribute.value_type.to_string())],
+                                 vec![Value::from(attribute.cardinality.to_string())],
+                                 unique,
+                                 attribute.doc.iter().map(|d| Value::from(&d[..])).collect()]);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attribute, Cardinality, Error, Unique, ValueType};
+    use data::{Basis, Datum, Db, Value};
+    use data::Error as DbError;
+
+    fn artists() -> Db {
+        let mut db = Db::new();
+        let name = Attribute::parse(&["artist/name", "str", "one", "identity"], None).unwrap();
+        let commit = db.prepare_declare(name, None).unwrap();
+        db.apply(commit);
+        db.insert(vec![Datum::new(3, "artist/name", "Led Zeppelin", 1968)], vec![], 1).unwrap();
+        db
+    }
+
+    #[test]
+    fn parse_declaration() {
+        let attribute = Attribute::parse(&["gdp/value", "float", "unique"],
+                                         Some("GDP in current US$".to_owned()))
+                            .unwrap();
+        assert_eq!(ValueType::Float, attribute.value_type);
+        assert_eq!(Cardinality::Many, attribute.cardinality);
+        assert_eq!(Some(Unique::Value), attribute.unique);
+        assert_eq!(Some("GDP in current US$".to_owned()), attribute.doc);
+        assert_eq!(None, Attribute::parse(&["gdp/value", "decimal"], None));
+        assert_eq!(None, Attribute::parse(&["gdp/value", "float", "twice"], None));
+    }
+
+    #[test]
+    fn infer_types() {
+        assert_eq!(ValueType::Int, ValueType::infer(vec!["1968", "", "-3"].into_iter()));
+        assert_eq!(ValueType::Float, ValueType::infer(vec!["1968", "2.5"].into_iter()));
+        assert_eq!(ValueType::Bool, ValueType::infer(vec!["true", "FALSE"].into_iter()));
+        assert_eq!(ValueType::Timestamp,
+                   ValueType::infer(vec!["2016-05-01", "2016-05-01T12:30:00Z"].into_iter()));
+        assert_eq!(ValueType::Str, ValueType::infer(vec!["1968", "n/a"].into_iter()));
+        assert_eq!(ValueType::Str, ValueType::infer(vec!["", ""].into_iter()));
+
+        assert_eq!(Some(Value::Float(3.0)), ValueType::Float.convert("3"));
+        assert_eq!(Some(Value::Timestamp(1462060800)),
+                   ValueType::Timestamp.convert("2016-05-01"));
+        assert_eq!(None, ValueType::Int.convert("3.5"));
+    }
+
+    #[test]
+    fn declarations_are_datums() {
+        let db = artists();
+        let name = db.schema.get("artist/name").unwrap();
+        assert_eq!(ValueType::Str, name.value_type);
+        assert_eq!(Cardinality::One, name.cardinality);
+        assert_eq!(Some(Unique::Identity), name.unique);
+        assert_eq!(vec!["artist/name", "str", "one", "identity", ""],
+                   db.schema.table().rows[0]
+                            .iter()
+                            .map(|c| c.iter().map(|v| v.to_string()).collect::<String>())
+                            .collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn enforce_declarations() {
+        let mut db = artists();
+
+        match db.insert(vec![Datum::new(5, "artist/name", Value::Int(1), 1970)], vec![], 1) {
+            Err(DbError::Schema(Error::WrongType(_, ValueType::Str, Value::Int(1)))) => (),
+            other => panic!("expected a type error, got {:?}", other),
+        }
+        match db.insert(vec![Datum::new(5, "artist/name", "Led Zeppelin", 1970)], vec![], 1) {
+            Err(DbError::Schema(Error::NotUnique(..))) => (),
+            other => panic!("expected a uniqueness error, got {:?}", other),
+        }
+        match db.insert(vec![Datum::new(5, "artist/name", "Queen", 1970),
+                             Datum::new(5, "artist/name", "Queen II", 1970)],
+                        vec![],
+                        1) {
+            Err(DbError::Schema(Error::ManyValues(..))) => (),
+            other => panic!("expected a cardinality error, got {:?}", other),
+        }
+
+        db.insert(vec![Datum::new(3, "artist/name", "Led Zeppelin II", 1969)], vec![], 0).unwrap();
+        let current = db.datums
+                        .rows()
+                        .enumerate()
+                        .filter(|&(p, ref r)| r.a == "artist/name" && db.visible(p, Basis::Current))
+                        .map(|(_, r)| r.v.to_string())
+                        .collect::<Vec<String>>();
+        assert_eq!(vec!["Led Zeppelin II"], current);
+    }
+
+    #[test]
+    fn declare_over_existing_datums() {
+        let mut db = Db::new();
+        db.insert(vec![Datum::new(1, "gdp/value", Value::Int(99), 1994),
+                       Datum::new(1, "gdp/country_name", "Aruba", 1994)],
+                  vec![],
+                  1)
+          .unwrap();
+
+        let value = Attribute::parse(&["gdp/value", "float"], None).unwrap();
+        assert!(db.prepare_declare(value, None).is_ok());
+        let name = Attribute::parse(&["gdp/country_name", "int"], None).unwrap();
+        assert!(db.prepare_declare(name, None).is_err());
+    }
+}
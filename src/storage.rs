@@ -0,0 +1,484 @@
+use memmap::Mmap;
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use data::{format_time, Datum, Value};
+use intern::{Dictionary, Symbols};
+
+/// A datum read out of `Storage`, borrowing its attribute and, unless it's mapped, its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row<'a> {
+    pub e: usize,
+    pub a: &'a str,
+    pub v: Cow<'a, Value>,
+    pub t: i64,
+    pub tx: usize,
+    pub added: bool,
+}
+
+impl<'a> Row<'a> {
+    pub fn to_datum(&self) -> Datum {
+        Datum {
+            e: self.e,
+            a: self.a.to_owned(),
+            v: self.v.clone().into_owned(),
+            t: self.t,
+            tx: self.tx,
+            added: self.added,
+        }
+    }
+
+    /// Whether both rows are about the same fact. Values are compared by their encoding, so
+    /// `1` and `1.0` are different facts and so are two NaNs with different bits.
+    pub fn same_fact(&self, other: &Row) -> bool {
+        self.e == other.e && self.a == other.a && encoded(&self.v) == encoded(&other.v)
+    }
+}
+
+impl<'a> From<&'a Datum> for Row<'a> {
+    fn from(datum: &'a Datum) -> Row<'a> {
+        Row {
+            e: datum.e,
+            a: &datum.a,
+            v: Cow::Borrowed(&datum.v),
+            t: datum.t,
+            tx: datum.tx,
+            added: datum.added,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Row<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "({}, {}, {}, {})", self.e, self.a, self.v, format_time(self.t)));
+        if !self.added {
+            try!(write!(f, " retracted"));
+        }
+        Ok(())
+    }
+}
+
+/// A datum as it's stored, its attribute and value are ids into the storage's dictionaries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fact {
+    pub e: usize,
+    pub a: u32,
+    pub v: u32,
+    pub t: i64,
+    pub tx: usize,
+    pub added: bool,
+}
+
+/// Where each column of a mapped base starts in its file, in bytes. Entities, times and
+/// transactions are 8 bytes a row, attribute and value ids 4 and `added` 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub e: usize,
+    pub a: usize,
+    pub v: usize,
+    pub t: usize,
+    pub tx: usize,
+    pub added: usize,
+}
+
+/// A mapped database file. Its columns aren't checksummed when it's opened, so ids and offsets
+/// read from them are checked as they're used instead: a damaged one is read as 0, or as a null
+/// value, and the first section found damaged is kept to fail whatever read it.
+pub struct MappedFile {
+    map: Mmap,
+    damaged: Mutex<Option<&'static str>>,
+}
+
+impl MappedFile {
+    pub fn new(map: Mmap) -> MappedFile {
+        MappedFile {
+            map: map,
+            damaged: Mutex::new(None),
+        }
+    }
+
+    pub fn damage(&self, section: &'static str) {
+        let mut damaged = self.damaged.lock().unwrap();
+        if damaged.is_none() {
+            *damaged = Some(section);
+        }
+    }
+
+    pub fn damaged(&self) -> Option<&'static str> {
+        *self.damaged.lock().unwrap()
+    }
+
+    // Returns `id` if it's below `bound`, otherwise marks `section` damaged
+    fn check<T>(&self, id: T, bound: T, section: &'static str) -> T
+        where T: PartialOrd + Default
+    {
+        if id < bound {
+            id
+        } else {
+            self.damage(section);
+            T::default()
+        }
+    }
+}
+
+struct Base {
+    map: Arc<MappedFile>,
+    len: usize,
+    layout: Layout,
+    // Ids read from the columns have to be below these
+    attributes: u32,
+    values: u32,
+    transactions: usize,
+}
+
+impl Base {
+    fn fact(&self, position: usize) -> Fact {
+        let bytes = bytes(&self.map);
+        let layout = &self.layout;
+        let map = &self.map;
+
+        Fact {
+            e: read_u64(bytes, layout.e + 8 * position) as usize,
+            a: map.check(read_u32(bytes, layout.a + 4 * position),
+                         self.attributes,
+                         "attribute ids"),
+            v: map.check(read_u32(bytes, layout.v + 4 * position), self.values, "value ids"),
+            t: read_u64(bytes, layout.t + 8 * position) as i64,
+            tx: map.check(read_u64(bytes, layout.tx + 8 * position) as usize,
+                          self.transactions,
+                          "transaction ids"),
+            added: bytes[layout.added + position] != 0,
+        }
+    }
+}
+
+/// The datums of a database in insertion order: a base read in place from a mapped file,
+/// followed by the datums appended since it was opened. Attributes and values are interned in
+/// dictionaries shared by both.
+#[derive(Clone)]
+pub struct Storage {
+    base: Option<Arc<Base>>,
+    tail: Vec<Fact>,
+    pub attributes: Symbols,
+    pub values: Dictionary,
+}
+
+impl Storage {
+    pub fn new() -> Storage {
+        Storage {
+            base: None,
+            tail: vec![],
+            attributes: Symbols::new(),
+            values: Dictionary::new(),
+        }
+    }
+
+    /// Reads the first `len` datums from the columns of `map` described by `layout`, their ids
+    /// point into `attributes`, `values` and the first `transactions` transactions.
+    pub fn mapped(map: Arc<MappedFile>,
+                  len: usize,
+                  layout: Layout,
+                  attributes: Symbols,
+                  values: Dictionary,
+                  transactions: usize)
+                  -> Storage {
+        let base = Base {
+            map: map,
+            len: len,
+            layout: layout,
+            attributes: attributes.len() as u32,
+            values: values.len() as u32,
+            // Files migrated from before transactions existed hold datums of transaction 0
+            transactions: cmp::max(transactions, 1),
+        };
+        Storage {
+            base: Some(Arc::new(base)),
+            tail: vec![],
+            attributes: attributes,
+            values: values,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.base_len() + self.tail.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn fact(&self, position: usize) -> Fact {
+        match self.base {
+            Some(ref base) if position < base.len => base.fact(position),
+            _ => self.tail[position - self.base_len()],
+        }
+    }
+
+    pub fn row(&self, position: usize) -> Row {
+        self.resolve(&self.fact(position))
+    }
+
+    pub fn resolve(&self, fact: &Fact) -> Row {
+        Row {
+            e: fact.e,
+            a: self.attributes.name(fact.a),
+            v: self.values.value(fact.v),
+            t: fact.t,
+            tx: fact.tx,
+            added: fact.added,
+        }
+    }
+
+    pub fn rows(&self) -> Rows {
+        Rows {
+            storage: self,
+            position: 0,
+        }
+    }
+
+    /// The attribute of the datum at `position`, without reading its value.
+    pub fn attribute(&self, position: usize) -> &str {
+        self.attributes.name(self.fact(position).a)
+    }
+
+    pub fn extend(&mut self, datums: Vec<Datum>) {
+        for datum in datums {
+            let fact = Fact {
+                e: datum.e,
+                a: self.attributes.intern(&datum.a),
+                v: self.values.intern(&datum.v),
+                t: datum.t,
+                tx: datum.tx,
+                added: datum.added,
+            };
+            self.tail.push(fact);
+        }
+    }
+
+    /// The first damaged section of the mapped file found while reading it, if any.
+    pub fn damaged(&self) -> Option<&'static str> {
+        self.base.as_ref().and_then(|base| base.map.damaged())
+    }
+
+    /// The number of datums held on the heap, added since the file was mapped.
+    pub fn appended(&self) -> usize {
+        self.tail.len()
+    }
+
+    fn base_len(&self) -> usize {
+        self.base.as_ref().map_or(0, |b| b.len)
+    }
+}
+
+impl From<Vec<Datum>> for Storage {
+    fn from(datums: Vec<Datum>) -> Storage {
+        let mut storage = Storage::new();
+        storage.extend(datums);
+        storage
+    }
+}
+
+impl PartialEq for Storage {
+    fn eq(&self, other: &Storage) -> bool {
+        self.len() == other.len() && self.rows().zip(other.rows()).all(|(l, r)| l == r)
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "Storage {{ mapped: {}, heap: {}, attributes: {}, values: {} }}",
+               self.base_len(),
+               self.appended(),
+               self.attributes.len(),
+               self.values.len())
+    }
+}
+
+pub struct Rows<'a> {
+    storage: &'a Storage,
+    position: usize,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Row<'a>;
+
+    fn next(&mut self) -> Option<Row<'a>> {
+        if self.position < self.storage.len() {
+            self.position += 1;
+            Some(self.storage.row(self.position - 1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Unsigned numbers, like index positions, either owned or read in place as 8 byte little
+/// endian numbers from a mapped file. Mapped columns hold where they start, their length and
+/// the bound every number in them is below.
+#[derive(Clone)]
+pub enum Column {
+    Heap(Vec<usize>),
+    Mapped(Arc<MappedFile>, usize, usize, usize),
+}
+
+impl Column {
+    pub fn len(&self) -> usize {
+        match *self {
+            Column::Heap(ref values) => values.len(),
+            Column::Mapped(_, _, len, _) => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> usize {
+        match *self {
+            Column::Heap(ref values) => values[i],
+            Column::Mapped(ref map, start, _, bound) => {
+                map.check(read_u64(bytes(map), start + 8 * i) as usize, bound, "indexes")
+            }
+        }
+    }
+
+    pub fn slice(&self, lower: usize, upper: usize) -> Vec<usize> {
+        (lower..upper).map(|i| self.get(i)).collect()
+    }
+}
+
+impl PartialEq for Column {
+    fn eq(&self, other: &Column) -> bool {
+        self.len() == other.len() && (0..self.len()).all(|i| self.get(i) == other.get(i))
+    }
+}
+
+impl fmt::Debug for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.slice(0, self.len())).finish()
+    }
+}
+
+// Snapshots are only ever renamed over a mapped file, never written in place, so the mapping
+// stays valid for as long as it is open
+pub fn bytes(map: &MappedFile) -> &[u8] {
+    unsafe { map.map.as_slice() }
+}
+
+/// Whether `bytes` are as long as the encoding of a value of their tag.
+pub fn well_formed(bytes: &[u8]) -> bool {
+    match bytes.first() {
+        Some(&0) | Some(&1) | Some(&4) | Some(&5) => bytes.len() == 9,
+        Some(&2) => bytes.len() == 2,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Appends a type tag followed by the value's little endian bytes, or its text.
+pub fn encode_value(value: &Value, bytes: &mut Vec<u8>) {
+    match *value {
+        Value::Int(i) => {
+            bytes.push(0);
+            push_u64(bytes, i as u64);
+        }
+        Value::Float(n) => {
+            bytes.push(1);
+            push_u64(bytes, unsafe { mem::transmute::<f64, u64>(n) });
+        }
+        Value::Bool(b) => {
+            bytes.push(2);
+            bytes.push(b as u8);
+        }
+        Value::Str(ref s) => {
+            bytes.push(3);
+            bytes.extend_from_slice(s.as_bytes());
+        }
+        Value::Timestamp(secs) => {
+            bytes.push(4);
+            push_u64(bytes, secs as u64);
+        }
+        Value::Ref(e) => {
+            bytes.push(5);
+            push_u64(bytes, e as u64);
+        }
+        Value::Null => bytes.push(6),
+    }
+}
+
+fn encoded(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    encode_value(value, &mut bytes);
+    bytes
+}
+
+pub fn decode_value(bytes: &[u8]) -> Value {
+    match bytes[0] {
+        0 => Value::Int(read_u64(bytes, 1) as i64),
+        1 => Value::Float(unsafe { mem::transmute::<u64, f64>(read_u64(bytes, 1)) }),
+        2 => Value::Bool(bytes[1] != 0),
+        4 => Value::Timestamp(read_u64(bytes, 1) as i64),
+        5 => Value::Ref(read_u64(bytes, 1) as usize),
+        6 => Value::Null,
+        _ => Value::Str(String::from_utf8_lossy(&bytes[1..]).into_owned()),
+    }
+}
+
+pub fn push_u32(bytes: &mut Vec<u8>, n: u32) {
+    for i in 0..4 {
+        bytes.push((n >> (8 * i)) as u8);
+    }
+}
+
+pub fn push_u64(bytes: &mut Vec<u8>, n: u64) {
+    for i in 0..8 {
+        bytes.push((n >> (8 * i)) as u8);
+    }
+}
+
+pub fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    (0..4).fold(0, |n, i| n | (bytes[at + i] as u32) << (8 * i))
+}
+
+pub fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    (0..8).fold(0, |n, i| n | (bytes[at + i] as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_value, encode_value, Row, Storage};
+    use data::{Datum, Value};
+
+    #[test]
+    fn value_encoding() {
+        let values = vec![Value::Int(-87896095224),
+                          Value::Float(100.5),
+                          Value::Bool(true),
+                          Value::from("Led Zeppelin"),
+                          Value::Timestamp(1462060800),
+                          Value::Ref(42),
+                          Value::Null];
+        for value in values {
+            let mut bytes = vec![];
+            encode_value(&value, &mut bytes);
+            assert_eq!(value, decode_value(&bytes));
+        }
+    }
+
+    #[test]
+    fn heap_rows() {
+        let datums = vec![Datum::new(1, "artist/name", "Led Zeppelin", 1968),
+                          Datum::new(2, "artist/name", "Queen", 1970)];
+        let storage = Storage::from(datums.clone());
+        assert_eq!(2, storage.len());
+        assert_eq!(storage.fact(0).a, storage.fact(1).a);
+        assert_eq!("artist/name", storage.attribute(1));
+        assert_eq!(Row::from(&datums[1]), storage.row(1));
+        assert_eq!(datums,
+                   storage.rows().map(|r| r.to_datum()).collect::<Vec<Datum>>());
+    }
+}
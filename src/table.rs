@@ -0,0 +1,67 @@
+use std::cmp;
+use std::fmt;
+use std::iter;
+
+use data::Value;
+
+/// The values in one cell, an entity can hold several values for the same attribute.
+pub type Cell = Vec<Value>;
+
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new(columns: Vec<String>) -> Table {
+        Table {
+            columns: columns,
+            rows: vec![],
+        }
+    }
+}
+
+fn cell_text(cell: &[Value]) -> String {
+    cell.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = self.rows
+                       .iter()
+                       .map(|row| row.iter().map(|cell| cell_text(cell)).collect::<Vec<String>>())
+                       .collect::<Vec<Vec<String>>>();
+
+        let mut widths = self.columns.iter().map(|c| c.chars().count()).collect::<Vec<usize>>();
+        for row in &rows {
+            for (i, text) in row.iter().enumerate() {
+                widths[i] = cmp::max(widths[i], text.chars().count());
+            }
+        }
+
+        let header = self.columns
+                         .iter()
+                         .enumerate()
+                         .map(|(i, c)| format!("{:1$}", c, widths[i]))
+                         .collect::<Vec<String>>();
+        try!(write!(f, "{}", header.join(" | ").trim_right()));
+
+        let rule = widths.iter()
+                         .map(|&w| iter::repeat('-').take(w).collect::<String>())
+                         .collect::<Vec<String>>();
+        try!(write!(f, "\n{}", rule.join("-+-")));
+
+        for row in &rows {
+            let cells = row.iter()
+                           .enumerate()
+                           .map(|(i, text)| format!("{:1$}", text, widths[i]))
+                           .collect::<Vec<String>>();
+            try!(write!(f, "\n{}", cells.join(" | ").trim_right()));
+        }
+        Ok(())
+    }
+}
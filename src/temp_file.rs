@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use time;
+
+static MADE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A file in the temp directory for a test, removed again when it goes out of scope. Names end
+/// in the time they were made at and a count, so tests running at once, and runs of the suite,
+/// don't share files.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str, extension: &str) -> TempFile {
+        let file = format!("entity-query-{}-{}-{}.{}",
+                           name,
+                           time::precise_time_ns(),
+                           MADE.fetch_add(1, Ordering::SeqCst),
+                           extension);
+        TempFile { path: env::temp_dir().join(file) }
+    }
+
+    /// The file named like this one followed by `suffix`, like the log kept next to a database.
+    pub fn with_suffix(&self, suffix: &str) -> TempFile {
+        TempFile { path: PathBuf::from(format!("{}{}", self.path.display(), suffix)) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,255 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize as serialize;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use data::{Commit, Db, Error};
+use format;
+
+/// The log is folded into the snapshot once it holds this many commits.
+const CHECKPOINT_EVERY: usize = 16;
+
+/// An append-only log of the commits applied since the last snapshot of the database. Each
+/// record is the length of the encoded commit as 8 little endian bytes, then the commit. A
+/// record of length 0 marks where the database was emptied.
+pub struct Wal {
+    snapshot: PathBuf,
+    file: File,
+    commits: usize,
+}
+
+impl Wal {
+    /// Loads the snapshot at `snapshot`, if there is one, and replays the log kept next to it.
+    pub fn open(snapshot: &str) -> Result<(Db, Wal), Error> {
+        let path = log_path(snapshot);
+        let mut db = if Path::new(snapshot).exists() {
+            try!(Db::from_file(snapshot))
+        } else {
+            Db::new()
+        };
+
+        let commits = try!(replay(&path, &mut db));
+        try!(db.check());
+        let file = try!(OpenOptions::new().create(true).append(true).open(&path));
+
+        let wal = Wal {
+            snapshot: PathBuf::from(snapshot),
+            file: file,
+            commits: commits,
+        };
+        Ok((db, wal))
+    }
+
+    /// Logs `commit` and waits for it to reach the disk before applying it to `db`. Errors mean
+    /// the commit wasn't applied.
+    pub fn commit(&mut self, db: &mut Db, commit: Commit) -> Result<usize, Error> {
+        // A commit prepared from a damaged file isn't logged
+        try!(db.check());
+        let encoded = try!(serialize::encode(&commit, SizeLimit::Infinite));
+        let mut record = length_bytes(encoded.len() as u64).to_vec();
+        record.extend(encoded);
+        try!(self.append(&record));
+
+        let tx = db.apply(commit);
+        self.commits += 1;
+        // The commit holds either way, a checkpoint that fails is tried again on the next one
+        if self.commits >= CHECKPOINT_EVERY {
+            let _ = self.checkpoint(db);
+        }
+        Ok(tx)
+    }
+
+    /// Empties `db`, logging it first so a replay doesn't bring back what the snapshot holds.
+    pub fn reset(&mut self, db: &mut Db) -> Result<(), Error> {
+        try!(self.append(&length_bytes(0)));
+        *db = Db::new();
+        self.commits += 1;
+        Ok(())
+    }
+
+    /// Writes `db` to a new snapshot, renames it over the old one and empties the log. `db` is
+    /// then read back from the snapshot, so the datums it held on the heap are mapped instead.
+    pub fn checkpoint(&mut self, db: &mut Db) -> Result<(), Error> {
+        let tmp = PathBuf::from(format!("{}.tmp", self.snapshot.display()));
+        try!(db.save(&tmp));
+        try!(fs::rename(&tmp, &self.snapshot));
+        // The rename only survives a crash once the directory holding it is synced
+        let dir = match self.snapshot.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        try!(try!(File::open(dir)).sync_all());
+
+        try!(self.file.set_len(0));
+        try!(self.file.sync_all());
+        self.commits = 0;
+        *db = try!(format::read(&self.snapshot));
+        Ok(())
+    }
+
+    // Appends `record` and waits for it to reach the disk. A record only partly written would
+    // hide every record appended after it, so it's cut off again.
+    fn append(&mut self, record: &[u8]) -> Result<(), Error> {
+        let len = try!(self.file.metadata()).len();
+        if let Err(err) = self.file.write_all(record) {
+            try!(self.file.set_len(len));
+            return Err(Error::Io(err));
+        }
+        try!(self.file.sync_data());
+        Ok(())
+    }
+}
+
+fn log_path(snapshot: &str) -> PathBuf {
+    PathBuf::from(format!("{}.wal", snapshot))
+}
+
+// Applies every complete record to `db`, skipping those already in the snapshot in case the
+// last checkpoint didn't get to empty the log. Returns the number of records in the log.
+fn replay(path: &Path, db: &mut Db) -> Result<usize, Error> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut bytes = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+
+    let mut records = vec![];
+    let mut position = 0;
+    while position + 8 <= bytes.len() {
+        let end = position + 8 + read_length(&bytes[position..position + 8]) as usize;
+        if end > bytes.len() {
+            break;
+        }
+        records.push((position + 8, end));
+        position = end;
+    }
+
+    // A record cut short by a crash was never acknowledged, drop it before appending again
+    if position < bytes.len() {
+        let file = try!(OpenOptions::new().write(true).open(path));
+        try!(file.set_len(position as u64));
+    }
+
+    // Nothing before the last reset survives it, whether the snapshot was written before or
+    // after it
+    let first = match records.iter().rposition(|&(start, end)| start == end) {
+        Some(reset) => {
+            *db = Db::new();
+            reset + 1
+        }
+        None => 0,
+    };
+    for &(start, end) in &records[first..] {
+        let commit: Commit = try!(serialize::decode(&bytes[start..end]));
+        if commit.transaction.id >= db.transactions.len() {
+            db.apply(commit);
+        }
+    }
+    Ok(records.len())
+}
+
+fn length_bytes(len: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (len >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+fn read_length(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |len, (i, &byte)| len | (byte as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::Wal;
+    use data::{Datum, Db};
+    use temp_file::TempFile;
+
+    // A database file and the log kept next to it
+    fn snapshot(name: &str) -> (TempFile, TempFile) {
+        let file = TempFile::new(name, "db");
+        let log = file.with_suffix(".wal");
+        (file, log)
+    }
+
+    fn commit(wal: &mut Wal, db: &mut Db, eid: usize, name: &str) {
+        let datums = vec![Datum::new(eid, "artist/name", name, 1968)];
+        let commit = db.prepare_insert(datums, vec![], 1, Some("artists.csv".to_owned()), None)
+                       .unwrap();
+        wal.commit(db, commit).unwrap();
+    }
+
+    #[test]
+    fn replay_log() {
+        let (file, _log) = snapshot("replay");
+        let path = file.to_str();
+        let (mut db, mut wal) = Wal::open(path).unwrap();
+        commit(&mut wal, &mut db, 1, "Led Zeppelin");
+        commit(&mut wal, &mut db, 3, "Queen");
+        drop(wal);
+
+        let (replayed, _) = Wal::open(path).unwrap();
+        assert_eq!(db, replayed);
+        assert_eq!(2, replayed.transactions.len());
+    }
+
+    #[test]
+    fn drop_torn_record() {
+        let (file, log) = snapshot("torn");
+        let path = file.to_str();
+        let (mut db, mut wal) = Wal::open(path).unwrap();
+        commit(&mut wal, &mut db, 1, "Led Zeppelin");
+        drop(wal);
+
+        let len = fs::metadata(log.path()).unwrap().len();
+        let mut torn = OpenOptions::new().append(true).open(log.path()).unwrap();
+        torn.write_all(&[42, 0, 0, 0, 0, 0, 0, 0, 1, 2]).unwrap();
+        drop(torn);
+
+        let (replayed, _) = Wal::open(path).unwrap();
+        assert_eq!(db, replayed);
+        assert_eq!(len, fs::metadata(log.path()).unwrap().len());
+    }
+
+    #[test]
+    fn checkpoint_snapshot() {
+        let (file, _log) = snapshot("checkpoint");
+        let path = file.to_str();
+        let (mut db, mut wal) = Wal::open(path).unwrap();
+        commit(&mut wal, &mut db, 1, "Led Zeppelin");
+        wal.checkpoint(&mut db).unwrap();
+        assert_eq!(0, db.datums.appended());
+        commit(&mut wal, &mut db, 3, "Queen");
+        assert_eq!(1, db.datums.appended());
+        drop(wal);
+
+        let (replayed, _) = Wal::open(path).unwrap();
+        assert_eq!(db, replayed);
+        assert_eq!(1, Db::from_file(path).unwrap().transactions.len());
+    }
+
+    #[test]
+    fn replay_reset() {
+        let (file, _log) = snapshot("reset");
+        let path = file.to_str();
+        let (mut db, mut wal) = Wal::open(path).unwrap();
+        commit(&mut wal, &mut db, 1, "Led Zeppelin");
+        wal.checkpoint(&mut db).unwrap();
+        wal.reset(&mut db).unwrap();
+        commit(&mut wal, &mut db, 1, "Queen");
+        drop(wal);
+
+        // The snapshot still holds Led Zeppelin, the log empties it first
+        let (replayed, _) = Wal::open(path).unwrap();
+        assert_eq!(db, replayed);
+        assert_eq!(1, replayed.transactions.len());
+    }
+}